@@ -76,8 +76,8 @@ pub fn get_path_for_dev(dev: u64) -> Result<String, CttyError> {
     // Check all devices in /dev/pts/* and /dev/tty* for a match
     let patterns = ["/dev/pts/*", "/dev/tty"];
 
-    for i in 0..patterns.len() {
-        for entry in glob(patterns[i]).unwrap() {
+    for pattern in &patterns {
+        for entry in glob(pattern).unwrap() {
             let path = match entry {
                 Ok(p) => p,
                 Err(_) => {
@@ -0,0 +1,142 @@
+use anyhow::{Context, Result};
+use rusqlite::backup::{Backup, StepResult};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{error, info};
+
+use crate::db::Database;
+
+const SNAPSHOT_PREFIX: &str = "histdb-";
+/// The first bytes of every sqlite database file
+const SQLITE_HEADER: &[u8] = b"SQLite format 3\0";
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BackupSummary {
+    pub path: PathBuf,
+    pub bytes: u64,
+}
+
+/// Write a consistent copy of the database behind `src` to `dest`, using sqlite's online backup
+/// API. The copy is made in one step, inside a single read transaction, so in WAL mode it
+/// neither blocks writers nor gets restarted by them. It's written next to `dest` and renamed
+/// into place, so `dest` is never a partial copy. An existing `dest` is only replaced if it's a
+/// database too, such as an older backup.
+pub fn backup(src: &Connection, dest: &Path) -> Result<BackupSummary> {
+    if !replaceable(dest)? {
+        anyhow::bail!(
+            "{} already exists and isn't a sqlite database, so it wasn't replaced",
+            dest.display()
+        );
+    }
+    let mut tmp = dest.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let _ = std::fs::remove_file(&tmp);
+
+    let mut dst =
+        Connection::open(&tmp).with_context(|| format!("Unable to create {}", tmp.display()))?;
+    match Backup::new(src, &mut dst)?.step(-1)? {
+        StepResult::Done => {}
+        x => anyhow::bail!("Backup didn't finish: {:?}", x),
+    }
+    // a self-contained file, rather than one that needs its -wal alongside it
+    dst.execute_batch("PRAGMA journal_mode = DELETE;")?;
+    drop(dst);
+
+    std::fs::rename(&tmp, dest)
+        .with_context(|| format!("Unable to move backup into place at {}", dest.display()))?;
+    Ok(BackupSummary {
+        path: dest.to_path_buf(),
+        bytes: std::fs::metadata(dest)?.len(),
+    })
+}
+
+/// Whether `path` is free, or holds a sqlite database (an empty file counts as one)
+fn replaceable(path: &Path) -> Result<bool> {
+    let mut file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(true),
+        Err(e) => return Err(e).with_context(|| format!("Unable to open {}", path.display())),
+    };
+    let mut header = Vec::new();
+    (&mut file)
+        .take(SQLITE_HEADER.len() as u64)
+        .read_to_end(&mut header)?;
+    Ok(header.is_empty() || header == SQLITE_HEADER)
+}
+
+/// Every `interval`, write a snapshot into `dir` and delete all but the newest `keep`
+pub async fn snapshot_forever(
+    db: Database,
+    dir: PathBuf,
+    interval: Duration,
+    keep: usize,
+) -> Result<()> {
+    std::fs::create_dir_all(&dir)
+        .with_context(|| format!("Unable to create snapshot directory {}", dir.display()))?;
+    info!(
+        "Snapshotting the database to {} every {:?}, keeping {}",
+        dir.display(),
+        interval,
+        keep
+    );
+    loop {
+        tokio::time::sleep(interval).await;
+        let dest = dir.join(format!(
+            "{}{}.db",
+            SNAPSHOT_PREFIX,
+            chrono::Local::now().format("%Y%m%d-%H%M%S")
+        ));
+        match db.read(move |con| backup(con, &dest)).await {
+            Ok(summary) => info!(
+                "Wrote snapshot {} ({} bytes)",
+                summary.path.display(),
+                summary.bytes
+            ),
+            Err(e) => error!("Writing snapshot: {:#}", e),
+        }
+        if let Err(e) = remove_old_snapshots(&dir, keep) {
+            error!("Removing old snapshots: {:#}", e);
+        }
+    }
+}
+
+fn remove_old_snapshots(dir: &Path, keep: usize) -> Result<()> {
+    let mut snapshots: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            name.starts_with(SNAPSHOT_PREFIX) && name.ends_with(".db")
+        })
+        .collect();
+    // the timestamp in the name sorts chronologically
+    snapshots.sort();
+    let n_old = snapshots.len().saturating_sub(keep);
+    for path in &snapshots[..n_old] {
+        info!("Removing old snapshot {}", path.display());
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_backup_replaces_only_databases() {
+    let dir = std::env::temp_dir().join(format!("history-test-backup-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let con = Connection::open_in_memory().unwrap();
+    crate::schema::create_schema(&con).unwrap();
+
+    let dest = dir.join("copy.db");
+    backup(&con, &dest).unwrap();
+    // an older backup is replaced
+    backup(&con, &dest).unwrap();
+    let other = dir.join("notes.txt");
+    std::fs::write(&other, "not a database").unwrap();
+    assert!(backup(&con, &other).is_err());
+    assert_eq!(std::fs::read_to_string(&other).unwrap(), "not a database");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,42 @@
+use anyhow::{Context, Result};
+use clap::Parser;
+use std::path::PathBuf;
+use tarpc::context;
+
+use crate::backup::backup;
+
+/// Write a consistent copy of the history database, safe to take while the server is running
+#[derive(Parser, Debug)]
+pub struct BackupOptions {
+    /// Where to write the copy. Without --from, the running server writes it, so this has to be
+    /// run on the server's host. An existing file is only replaced if it's a database too.
+    #[clap(value_name = "DEST")]
+    dest: PathBuf,
+
+    /// Copy DB directly rather than through the server
+    #[clap(value_name = "DB", long)]
+    from: Option<PathBuf>,
+}
+
+pub async fn backup_main(options: BackupOptions) -> Result<()> {
+    let summary = match options.from {
+        Some(from) => {
+            let con = rusqlite::Connection::open_with_flags(
+                &from,
+                rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY,
+            )
+            .with_context(|| format!("Unable to open {}", from.display()))?;
+            backup(&con, &options.dest)?
+        }
+        None => {
+            let dest = match options.dest.is_absolute() {
+                true => options.dest,
+                false => std::env::current_dir()?.join(options.dest),
+            };
+            let client = super::connect_admin().await?;
+            client.backup(context::current(), dest).await??
+        }
+    };
+    println!("Wrote {} ({} bytes)", summary.path.display(), summary.bytes);
+    Ok(())
+}
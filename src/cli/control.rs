@@ -0,0 +1,124 @@
+use anyhow::{anyhow, Context, Result};
+use clap::{Parser, Subcommand};
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::pidfile::{server_state, ServerState};
+
+/// Start, stop or check on the server for a database on this machine
+#[derive(Parser, Debug)]
+pub struct ServerControlOptions {
+    #[clap(subcommand)]
+    action: Action,
+
+    /// The server's database (default: ~/.histdb.db, as set up by --eval)
+    #[clap(value_name = "DB", long, global = true)]
+    db: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Action {
+    /// Start the server in the background, unless it's already running
+    Start(StartOptions),
+    /// Stop the server
+    Stop,
+    /// Show whether the server is running
+    Status,
+    /// Stop the server, then start it again
+    Restart(StartOptions),
+}
+
+#[derive(Parser, Debug)]
+struct StartOptions {
+    /// Options for the server, e.g. -- --metrics-addr 127.0.0.1:29081
+    #[clap(last = true)]
+    server_args: Vec<String>,
+}
+
+/// How long to wait for the server to come up or go away
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+pub async fn server_control_main(options: ServerControlOptions) -> Result<()> {
+    let db = match options.db {
+        Some(db) => db,
+        None => {
+            PathBuf::from(std::env::var("HOME").context("Unable to read $HOME")?).join(".histdb.db")
+        }
+    };
+    match options.action {
+        Action::Start(start_options) => start(&db, &start_options.server_args),
+        Action::Stop => stop(&db),
+        Action::Status => {
+            match server_state(&db)? {
+                ServerState::Running(Some(pid)) => println!("running, pid {}", pid),
+                ServerState::Running(None) => println!("starting"),
+                ServerState::Stale(pid) => {
+                    println!("not running (pid {} exited without cleaning up)", pid)
+                }
+                ServerState::Stopped => println!("not running"),
+            }
+            Ok(())
+        }
+        Action::Restart(start_options) => {
+            stop(&db)?;
+            start(&db, &start_options.server_args)
+        }
+    }
+}
+
+fn start(db: &Path, server_args: &[String]) -> Result<()> {
+    if let ServerState::Running(pid) = server_state(db)? {
+        println!("already running, pid {}", pid.unwrap_or_default());
+        return Ok(());
+    }
+    let status = std::process::Command::new(std::env::current_exe()?)
+        .env("__history_mode", "server")
+        .arg("--daemonize")
+        .args(server_args)
+        .arg(db)
+        .status()
+        .context("Unable to start the server")?;
+    if !status.success() {
+        anyhow::bail!("The server exited with {}", status);
+    }
+    let pid = wait_for(|| match server_state(db)? {
+        ServerState::Running(Some(pid)) => Ok(Some(pid)),
+        _ => Ok(None),
+    })
+    .context("The server didn't start. See /tmp/history-daemon.log")?;
+    println!("started, pid {}", pid);
+    Ok(())
+}
+
+fn stop(db: &Path) -> Result<()> {
+    let pid = match server_state(db)? {
+        ServerState::Running(Some(pid)) => pid,
+        ServerState::Running(None) => anyhow::bail!("The server is still starting"),
+        ServerState::Stale(_) | ServerState::Stopped => {
+            println!("not running");
+            return Ok(());
+        }
+    };
+    kill(Pid::from_raw(pid), Signal::SIGTERM)
+        .with_context(|| format!("Unable to signal pid {}", pid))?;
+    wait_for(|| match server_state(db)? {
+        ServerState::Running(_) => Ok(None),
+        _ => Ok(Some(())),
+    })
+    .with_context(|| format!("pid {} is still running", pid))?;
+    println!("stopped pid {}", pid);
+    Ok(())
+}
+
+fn wait_for<T>(mut check: impl FnMut() -> Result<Option<T>>) -> Result<T> {
+    let start = Instant::now();
+    while start.elapsed() < TIMEOUT {
+        if let Some(x) = check()? {
+            return Ok(x);
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    Err(anyhow!("Timed out after {:?}", TIMEOUT))
+}
@@ -1,21 +1,107 @@
 use anyhow::{anyhow, Context, Result};
 
+use crate::config::Config;
+use crate::local::LOCAL_SERVER;
 use crate::util::addr_routes_to_me;
 
+/// Commands longer than this are sent over TCP rather than UDP, so that the datagram fits in
+/// a typical 1500-byte MTU and doesn't depend on fragments arriving
+const MAX_DATAGRAM_COMMAND_BYTES: usize = 1200;
+
+/// Context the hook can send with each entry, and the bash that adds it to the CTX array.
+/// REPO, REPO_ID and BRANCH come from __history_git.
+const CONTEXT_FIELDS: &[(&str, &str)] = &[
+    ("repo", r#"[[ -n "$REPO" ]] && CTX+=("repo=$REPO")"#),
+    (
+        "repo_id",
+        r#"[[ -n "$REPO_ID" ]] && CTX+=("repo_id=$REPO_ID")"#,
+    ),
+    ("branch", r#"[[ -n "$BRANCH" ]] && CTX+=("branch=$BRANCH")"#),
+    (
+        "venv",
+        r#"[[ -n "$VIRTUAL_ENV" ]] && CTX+=("venv=$VIRTUAL_ENV")"#,
+    ),
+    (
+        "conda",
+        r#"[[ -n "$CONDA_DEFAULT_ENV" ]] && CTX+=("conda=$CONDA_DEFAULT_ENV")"#,
+    ),
+    ("user", r#"[[ -n "$USER" ]] && CTX+=("user=$USER")"#),
+    ("euid", r#"CTX+=("euid=$EUID")"#),
+    (
+        "ssh",
+        r#"[[ -n "$SSH_CONNECTION" ]] && CTX+=("ssh=${SSH_CONNECTION%% *}")"#,
+    ),
+    (
+        "tmux",
+        r#"[[ -n "$TMUX_PANE" ]] && CTX+=("tmux=$TMUX_PANE")"#,
+    ),
+    ("shlvl", r#"CTX+=("shlvl=$SHLVL")"#),
+];
+
+/// The bash that collects the context fields named in the config file, or all of them
+fn context_snippet(fields: Option<Vec<String>>) -> Result<String> {
+    let fields = fields.unwrap_or_else(|| {
+        CONTEXT_FIELDS
+            .iter()
+            .map(|(name, _)| name.to_string())
+            .collect()
+    });
+    let mut lines = Vec::new();
+    if fields
+        .iter()
+        .any(|f| f == "repo" || f == "repo_id" || f == "branch")
+    {
+        lines.push("local REPO REPO_ID BRANCH; __history_git");
+    }
+    for field in &fields {
+        match CONTEXT_FIELDS.iter().find(|(name, _)| name == field) {
+            Some((_, bash)) => lines.push(bash),
+            None => anyhow::bail!(
+                "Unknown context field {:?} in the config file. The choices are {}",
+                field,
+                CONTEXT_FIELDS
+                    .iter()
+                    .map(|(name, _)| *name)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+    Ok(lines.join("\n    "))
+}
+
 /// show text that should be sourced into the bash shell with eval "$(history --eval)"
-pub async fn show_bash_eval_string(server_addr: String) -> Result<()> {
+pub async fn show_bash_eval_string(
+    server_addr: String,
+    replica_addr: Option<String>,
+) -> Result<()> {
     let current_exe = std::env::current_exe()
         .context("Unable to get current executable name")?
         .into_os_string()
         .into_string()
         .map_err(|_| anyhow!("Unable to format current executable name as a UTF-8 string"))?;
 
+    let local = server_addr == LOCAL_SERVER;
+    if local && replica_addr.is_some() {
+        anyhow::bail!("A local server can't have a replica");
+    }
+    let config = Config::load()?;
+    let reliable = config.ingest.reliable;
+    let context = context_snippet(config.context.fields)?;
     let runserver = format!(
-        "__history_mode=\"server\" {} --daemonize $HOME/.histdb.db",
-        current_exe
+        "__history_mode=\"server\" {} --daemonize{} $HOME/.histdb.db",
+        current_exe,
+        if local { " --local" } else { "" }
     );
-    if addr_routes_to_me(&server_addr).await? {
+    if local || addr_routes_to_me(&server_addr).await? {
         println!("{}", runserver);
+    } else if let Some(replica_addr) = &replica_addr {
+        if addr_routes_to_me(replica_addr).await? {
+            println!(
+                "__history_mode=\"server\" {} --daemonize --replica-of {} $HOME/.histdb.db",
+                current_exe, server_addr
+            );
+        }
     }
 
     // This is a bit fiddly, so here's an explanation of what we're trying to do:
@@ -32,12 +118,21 @@ pub async fn show_bash_eval_string(server_addr: String) -> Result<()> {
     //      careful to deal with the case where the user already has a PROMPT_COMMAND (put ours at the
     //      end) and when we're called multiple times (only puty it in once)
     //    5. The command that we actually run is supposed to forward the information (exit code, pwd,
-    //      and last executed command from the history builtin) to the server. Bash has this insane
-    //      feature where you can use the pseudo-file /dev/udp/host/port to send UDP messages right
-    //      from the shell (https://tightlycoupled.io/send-udp-messages-with-dev-udp/). That's nice
-    //      because it means we don't need to invoke an extra process from within the callback, which
-    //      is always a risk (what if the process is slow, hangs, crashes). In particular if you invoke
-    //      a process from PROMPT_COMMAND and it hangs, now your shell is hung.
+    //      and the last executed command and its number, from the fc builtin) to the server. Bash
+    //      has this insane feature where you can use the pseudo-file /dev/udp/host/port to send UDP
+    //      messages right from the shell
+    //      (https://tightlycoupled.io/send-udp-messages-with-dev-udp/). That's nice because it means
+    //      we don't need to invoke an extra process from within the callback, which is always a
+    //      risk (what if the process is slow, hangs, crashes). In particular if you invoke a process
+    //      from PROMPT_COMMAND and it hangs, now your shell is hung.
+    //    6. In local mode (--eval local) the server listens on a Unix socket instead, which bash
+    //      can't write to, so the hook does pipe the message through this binary after all. It
+    //      sends without waiting, so a stuck server still can't hang the shell.
+    //    7. Entries too long for a datagram or spanning several lines, and every entry if
+    //      [ingest] reliable is set in the config file, go over TCP instead, and are retried until
+    //      the server acknowledges them. That does mean starting a process, and one that might wait
+    //      on the server, so it's run in the background, in a subshell so that it stays out of the
+    //      jobs table.
     //
 
     // Note: I've been through a few different prior versions of the design here.
@@ -87,11 +182,107 @@ pub async fn show_bash_eval_string(server_addr: String) -> Result<()> {
     //   to freaking die. And also the coproc-specific env variables don't actually update properly when it
     //   dies.
 
+    println!(
+        "{}",
+        hook_script(
+            &current_exe,
+            &server_addr,
+            replica_addr.as_deref(),
+            local,
+            reliable,
+            &context
+        )
+    );
+
+    Ok(())
+}
+
+/// The bash that sends each command to the server at `server_addr` from PROMPT_COMMAND, and
+/// binds ctrl-r
+fn hook_script(
+    exe: &str,
+    server_addr: &str,
+    replica_addr: Option<&str>,
+    local: bool,
+    reliable: bool,
+    context: &str,
+) -> String {
     let cmd = r#"export __history_server="@history_ADDR@"
-__history_session=$(tty); __history_session="${__history_session/\/dev\/pts\//}"
+@history_REPLICA@
+__history_session="@history_SESSION@"
+__history_tty=$(tty 2>/dev/null); __history_tty="${__history_tty#/dev/pts/}"
+[[ "$__history_tty" =~ ^[0-9]+$ ]] || __history_tty=""
+__history_reliable="@history_RELIABLE@"
+# the number of the last entry the previous shell left in HISTFILE, once the first prompt finds it
+__history_first=""
+# sets REPO, BRANCH and REPO_ID (the origin remote's URL, or else the first remote's) from the
+# enclosing git checkout, without starting any processes
+__history_git() {
+    local dir="$PWD" git head line section first
+    REPO="" BRANCH="" REPO_ID=""
+    while :; do
+        if [[ -e "$dir/.git" ]]; then
+            REPO="${dir:-/}"
+            git="$dir/.git"
+            # worktrees and submodules have a file pointing at the real git dir
+            if [[ -f "$git" ]]; then
+                { read -r head < "$git"; } 2>/dev/null
+                git="${head#gitdir: }"
+                [[ "$git" == /* ]] || git="$dir/$git"
+            fi
+            { read -r head < "$git/HEAD"; } 2>/dev/null
+            case "$head" in
+                "ref: refs/heads/"*) BRANCH="${head#ref: refs/heads/}" ;;
+                *) BRANCH="${head:0:12}" ;;
+            esac
+            # a worktree's config is in the git dir of the checkout it was made from
+            if [[ -f "$git/commondir" ]]; then
+                { read -r line < "$git/commondir"; } 2>/dev/null
+                [[ "$line" == /* ]] && git="$line" || git="$git/$line"
+            fi
+            while read -r line; do
+                case "$line" in
+                    "["*) section="$line" ;;
+                    url*=*)
+                        line="${line#*=}"; line="${line# }"
+                        if [[ "$section" == '[remote "origin"]' ]]; then
+                            REPO_ID="$line"; break
+                        fi
+                        [[ "$section" == '[remote "'* ]] && first="${first:-$line}"
+                        ;;
+                esac
+            done 2>/dev/null < "$git/config"
+            REPO_ID="${REPO_ID:-$first}"
+            return
+        fi
+        [[ -z "$dir" ]] && return
+        dir="${dir%/*}"
+    done
+}
 __history() {
-    local EXIT="$?"
-    printf "%s\0%s\0%s\0%s\0%s" "$__history_session" "@history_HOSTNAME@" "$EXIT" "$(pwd)" "$(command history 1)" > /dev/udp/@history_ADDR@/@HISTORY_PORT@
+    local EXIT="$?" NUMBER="$((HISTCMD - 1))"
+    # HISTFILE is only read after .bashrc, so on the first prompt fc finds the last command of
+    # the shell that wrote it, which that shell already sent. Nothing numbered up to that is sent.
+    [[ -z "$__history_first" ]] && __history_first="$NUMBER"
+    ((NUMBER > __history_first)) || return 0
+    __history_first=0
+    # in the C locale, ${#CMD} counts bytes
+    local CMD LC_ALL=C
+    # the entry exactly as it's stored, after a tab and a space, whatever HISTTIMEFORMAT says
+    CMD="$(fc -ln -0)"
+    CMD="${CMD#$'\t '}"
+    local -a CTX=()
+    @history_CONTEXT@
+    local FMT=""
+    # a \0%s in the format for each context field
+    ((${#CTX[@]})) && printf -v FMT '\\0%%s%.0s' "${CTX[@]}"
+    # bash flushes printf's output at newlines, which would split a multi-line command across
+    # datagrams, so those go through the sender too
+    if [[ -n "$__history_reliable" || ${#CMD} -gt @history_MAX_DATAGRAM@ || "$CMD$PWD${CTX[*]}" == *$'\n'* ]]; then
+        (printf "v3\0%s\0%s\0%s\0%s\0%s\0%s$FMT\0%s" "$__history_session" "$__history_tty" "@history_HOSTNAME@" "$EXIT" "$(pwd)" "$NUMBER" "${CTX[@]}" "$CMD" | __history_mode=send __history_reliable=1 @history_EXE@ 2>/dev/null &)
+    else
+        printf "v3\0%s\0%s\0%s\0%s\0%s\0%s$FMT\0%s" "$__history_session" "$__history_tty" "@history_HOSTNAME@" "$EXIT" "$(pwd)" "$NUMBER" "${CTX[@]}" "$CMD" @history_SEND@
+    fi
 }
 
 unset -f __history_interactive
@@ -139,18 +330,93 @@ history() {
     if [[ $(command caller) == *"/etc/bashrc" ]]; then
         command history "$@"
     else
-    __history_pwd=$(pwd) @history_EXE@ "$@"
+    __history_pwd=$(pwd) __history_session="$__history_session" @history_EXE@ "$@"
     fi
 }
 "#;
 
-    println!(
-        "{}",
-        cmd.replace("@history_EXE@", &current_exe)
-            .replace("@history_ADDR@", &server_addr)
-            .replace("@history_HOSTNAME@", &*crate::MYHOSTNAME,)
-            .replace("@HISTORY_PORT@", &format!("{}", crate::HISTORY_PORT))
-    );
+    // first, since it has placeholders of its own
+    cmd.replace(
+        "@history_SEND@",
+        match local {
+            true => "| __history_mode=send @history_EXE@",
+            false => "> /dev/udp/@history_ADDR@/@HISTORY_PORT@",
+        },
+    )
+    .replace("@history_EXE@", exe)
+    .replace("@history_ADDR@", server_addr)
+    .replace(
+        "@history_REPLICA@",
+        &match replica_addr {
+            Some(replica_addr) => format!("export __history_replica=\"{}\"", replica_addr),
+            None => "unset __history_replica".to_string(),
+        },
+    )
+    .replace("@history_CONTEXT@", context)
+    .replace("@history_RELIABLE@", if reliable { "1" } else { "" })
+    .replace(
+        "@history_MAX_DATAGRAM@",
+        &format!("{}", MAX_DATAGRAM_COMMAND_BYTES),
+    )
+    .replace("@history_HOSTNAME@", &crate::MYHOSTNAME)
+    .replace("@history_SESSION@", &crate::util::new_session_id())
+    .replace("@HISTORY_PORT@", &format!("{}", crate::HISTORY_PORT))
+}
 
-    Ok(())
+#[test]
+fn test_new_shell_doesnt_resend_last_command() {
+    use std::io::Write;
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = std::env::temp_dir().join(format!("history-test-hook-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    // stands in for this binary, writing down what it's asked to send, one entry per line
+    let exe = dir.join("history");
+    std::fs::write(
+        &exe,
+        format!(
+            "#!/bin/sh\n[ \"$__history_mode\" = send ] && {{ cat; echo; }} >> {}/sent\n",
+            dir.display()
+        ),
+    )
+    .unwrap();
+    std::fs::set_permissions(&exe, std::fs::Permissions::from_mode(0o755)).unwrap();
+    std::fs::write(
+        dir.join("hook.sh"),
+        hook_script(exe.to_str().unwrap(), "local", None, true, false, ""),
+    )
+    .unwrap();
+    // the last shell's history, which bash reads in after the rcfile
+    std::fs::write(dir.join("hist"), "ls\nmake\n").unwrap();
+    std::fs::write(
+        dir.join("rc"),
+        format!("HISTFILE={0}/hist\nsource {0}/hook.sh\n", dir.display()),
+    )
+    .unwrap();
+
+    let mut bash = std::process::Command::new("bash")
+        .arg("--rcfile")
+        .arg(dir.join("rc"))
+        .arg("-i")
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+    bash.stdin
+        .take()
+        .unwrap()
+        .write_all(b"echo hi\nexit\n")
+        .unwrap();
+    bash.wait().unwrap();
+
+    let sent = std::fs::read(dir.join("sent")).unwrap_or_default();
+    let commands: Vec<_> = sent
+        .split(|&b| b == b'\n')
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| String::from_utf8_lossy(entry.rsplit(|&b| b == 0).next().unwrap()))
+        .collect();
+    assert_eq!(commands, ["echo hi"]);
+    std::fs::remove_dir_all(&dir).unwrap();
 }
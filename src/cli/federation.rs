@@ -0,0 +1,107 @@
+use anyhow::Result;
+use futures_util::future::join_all;
+use std::collections::BTreeSet;
+
+use crate::tcp::{HistoryQueryServiceClient, IsearchQuery, QueryResultRow};
+use tarpc::context;
+
+/// Our own history server, `own`, or one listed under `[federation]` in the config
+pub(super) struct Server {
+    pub name: String,
+    pub client: Result<HistoryQueryServiceClient>,
+}
+
+/// Connect to our own server and all of the configured ones at once, ours first. Ours is
+/// reached the usual way, falling back to its replica; a server that can't be reached is kept
+/// along with the reason, for the caller to warn about.
+pub(super) async fn connect_servers(servers: &[String], own: &str) -> Vec<Server> {
+    let names: BTreeSet<&String> = servers.iter().filter(|s| *s != own).collect();
+    let own = async {
+        Server {
+            name: own.to_string(),
+            client: super::connect().await,
+        }
+    };
+    let others = join_all(names.into_iter().map(|name| async move {
+        let client = tokio::time::timeout(super::CONNECT_TIMEOUT, super::connect_to(name))
+            .await
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("Timed out connecting to {}", name)));
+        Server {
+            name: name.clone(),
+            client,
+        }
+    }));
+    let (own, others) = futures_util::join!(own, others);
+    std::iter::once(own).chain(others).collect()
+}
+
+/// Ctrl-r across `servers`. Each server ranks its own results; they're interleaved rank by rank
+/// (in the order of `servers`), skipping commands already seen, so the nth result overall is
+/// stable as the user presses ctrl-r again. Servers that fail are left out, unless they all do.
+pub(super) async fn isearch_all(
+    servers: &[Server],
+    query: IsearchQuery,
+) -> Result<Vec<QueryResultRow>> {
+    if let [Server {
+        client: Ok(client), ..
+    }] = servers
+    {
+        return Ok(client.isearch(context::current(), query).await??);
+    }
+    let offset = query.offset as usize;
+    let all_servers: Vec<_> = servers
+        .iter()
+        .filter_map(|s| s.client.as_ref().ok())
+        .collect();
+    // the first offset + 1 distinct commands can't come from deeper than that on any one server
+    let per_server = IsearchQuery {
+        limit: (offset + query.limit as usize) as u32,
+        offset: 0,
+        ..query
+    };
+    let results = join_all(
+        all_servers
+            .iter()
+            .map(|client| client.isearch(context::current(), per_server.clone())),
+    )
+    .await;
+
+    let mut lists = Vec::new();
+    let mut first_error = None;
+    for result in results {
+        match result {
+            Ok(Ok(rows)) => lists.push(rows.into_iter()),
+            Ok(Err(e)) => {
+                first_error.get_or_insert(anyhow::Error::from(e));
+            }
+            Err(e) => {
+                first_error.get_or_insert(anyhow::Error::from(e));
+            }
+        }
+    }
+    if let (true, Some(e)) = (lists.is_empty(), first_error) {
+        return Err(e);
+    }
+
+    let mut seen = std::collections::HashSet::new();
+    let mut merged = Vec::new();
+    loop {
+        let mut any = false;
+        for list in lists.iter_mut() {
+            if let Some(row) = list.next() {
+                any = true;
+                if seen.insert(row.argv.clone()) {
+                    merged.push(row);
+                }
+            }
+        }
+        if !any {
+            break;
+        }
+    }
+    Ok(merged
+        .into_iter()
+        .skip(offset)
+        .take(query.limit as usize)
+        .collect())
+}
@@ -1,4 +1,4 @@
-use anyhow::{Context, Result};
+use anyhow::Result;
 use crossterm::event::{KeyEvent, KeyModifiers};
 use crossterm::style::Print;
 use crossterm::terminal::{self, Clear};
@@ -9,15 +9,26 @@ use crossterm::{
 use std::fs::File;
 use std::io::{stdout, BufWriter, Write};
 use std::os::unix::io::FromRawFd;
-use tarpc::tokio_serde::formats::Bincode;
-use tarpc::{client, context};
 
-use crate::tcp::HistoryQueryServiceClient;
+use super::federation::{connect_servers, isearch_all, Server};
+use super::query::annotation_marker;
+use crate::config::Config;
 
 static PROMPT: &str = "(reverse-i-search)";
 static FAILED_PROMPT: &str = "(failed reverse-i-search)";
+static INCLUDE_FAILURES_PROMPT: &str = "(reverse-i-search +errors)";
+static INCLUDE_FAILURES_FAILED_PROMPT: &str = "(failed reverse-i-search +errors)";
 
-async fn main_loop(client: HistoryQueryServiceClient) -> Result<()> {
+/// Set HISTORY_HIDE_NOT_FOUND=1 to never offer commands that exited with 127 (command not found),
+/// unless failures are toggled back on with ctrl-x.
+fn hide_not_found() -> bool {
+    matches!(
+        std::env::var("HISTORY_HIDE_NOT_FOUND").as_deref(),
+        Ok("1") | Ok("true") | Ok("yes")
+    )
+}
+
+async fn main_loop(servers: Vec<Server>) -> Result<()> {
     let mut stdout = stdout();
     let mut term_dimensions = crossterm::terminal::size()?;
     let mut fd3 = BufWriter::new(unsafe { File::from_raw_fd(3) });
@@ -26,6 +37,8 @@ async fn main_loop(client: HistoryQueryServiceClient) -> Result<()> {
     let mut query = String::new();
     let mut last_match: Option<String> = None;
     let mut offset_from_end: u32 = 0;
+    let mut include_failures = false;
+    let hide_not_found = hide_not_found();
     write!(stdout, "{}`': ", PROMPT).unwrap();
     stdout.flush().unwrap();
 
@@ -111,6 +124,15 @@ async fn main_loop(client: HistoryQueryServiceClient) -> Result<()> {
                     x => x - 1,
                 };
             }
+            Event::Key(KeyEvent {
+                code: KeyCode::Char('x'),
+                modifiers: KeyModifiers::CONTROL,
+            }) => {
+                // toggle whether commands that failed are ranked normally, for when you're
+                // specifically hunting for the broken invocation
+                offset_from_end = 0;
+                include_failures = !include_failures;
+            }
             Event::Key(KeyEvent {
                 code: KeyCode::Backspace,
                 modifiers: KeyModifiers::NONE,
@@ -154,11 +176,22 @@ async fn main_loop(client: HistoryQueryServiceClient) -> Result<()> {
             limit: 1,
             dir: crate::CWD.to_string(),
             offset: offset_from_end,
+            include_failures,
+            hide_not_found,
         };
         //eprintln!("{:#?}", q);
-        let result = client.isearch(context::current(), q).await??;
-        match result.get(0).map(|x| x.argv.clone()) {
-            Some(c) => {
+        let result = isearch_all(&servers, q).await?;
+        let (prompt, failed_prompt) = match include_failures {
+            false => (PROMPT, FAILED_PROMPT),
+            true => (INCLUDE_FAILURES_PROMPT, INCLUDE_FAILURES_FAILED_PROMPT),
+        };
+        match result.first() {
+            Some(row) => {
+                let c = row.argv.clone();
+                // tags only, since a note could run on for lines
+                let marker = annotation_marker(&row.tags, None)
+                    .map(|x| format!("  {}", x))
+                    .unwrap_or_default();
                 crossterm::execute!(
                     stdout,
                     crossterm::cursor::MoveToPreviousLine(
@@ -166,13 +199,17 @@ async fn main_loop(client: HistoryQueryServiceClient) -> Result<()> {
                     ),
                     Print("\r"),
                     Clear(terminal::ClearType::FromCursorDown),
-                    Print(PROMPT),
+                    Print(prompt),
                     Print("`"),
                     Print(&query),
                     Print("': "),
-                    Print(highlight(&c, &query))
+                    Print(highlight(&c, &query)),
+                    Print(crossterm::style::Attribute::Dim),
+                    Print(&marker),
+                    Print(crossterm::style::Attribute::Reset),
                 )?;
-                last_n_term_chars_printed = (PROMPT.len() + query.len() + 4 + c.len()) as u16;
+                last_n_term_chars_printed =
+                    (prompt.len() + query.len() + 4 + c.len() + marker.len()) as u16;
                 last_match = Some(c);
             }
             None => {
@@ -183,7 +220,7 @@ async fn main_loop(client: HistoryQueryServiceClient) -> Result<()> {
                     ),
                     Print("\r"),
                     Clear(terminal::ClearType::FromCursorDown),
-                    Print(FAILED_PROMPT),
+                    Print(failed_prompt),
                     Print("`"),
                     Print(&query),
                     Print("': "),
@@ -194,7 +231,7 @@ async fn main_loop(client: HistoryQueryServiceClient) -> Result<()> {
                             .unwrap_or("".to_string())
                     )
                 )?;
-                last_n_term_chars_printed = (FAILED_PROMPT.len()
+                last_n_term_chars_printed = (failed_prompt.len()
                     + query.len()
                     + 4
                     + last_match.as_ref().map(|x| x.len()).unwrap_or(0))
@@ -219,17 +256,26 @@ fn highlight(result: &str, query: &str) -> String {
 }
 
 pub async fn isearch_main() -> Result<()> {
-    let server = crate::HISTORY_SERVER
-        .as_ref()
-        .context("Unable to access environment variable '__history_server'")
-        .context("Did you forget to 'eval \"$(history --eval <server-name>)\"' in your .bashrc?")?;
-    let transport = tarpc::serde_transport::tcp::connect(
-        format!("{}:{}", server, crate::HISTORY_PORT),
-        Bincode::default,
-    )
-    .await?;
-
-    let client = HistoryQueryServiceClient::new(client::Config::default(), transport).spawn();
+    let config = Config::load()?;
+    let own = crate::HISTORY_SERVER.as_deref().unwrap_or_default();
+    let servers = match config.federation.isearch {
+        true => {
+            let servers = connect_servers(&config.federation.servers, own).await;
+            for server in &servers {
+                if let Err(e) = &server.client {
+                    tracing::warn!("Skipping history server {}: {:#}", server.name, e);
+                }
+            }
+            if servers.iter().all(|s| s.client.is_err()) {
+                anyhow::bail!("None of the history servers could be reached");
+            }
+            servers
+        }
+        false => vec![Server {
+            name: own.to_string(),
+            client: Ok(super::connect().await?),
+        }],
+    };
 
     if let Ok(q) = std::env::var("__history_query_debug") {
         let q = crate::tcp::IsearchQuery {
@@ -237,9 +283,11 @@ pub async fn isearch_main() -> Result<()> {
             limit: 10,
             dir: crate::CWD.to_string(),
             offset: 0,
+            include_failures: false,
+            hide_not_found: hide_not_found(),
         };
         eprintln!("{:#?}", q);
-        let result = client.isearch(context::current(), q).await??;
+        let result = isearch_all(&servers, q).await?;
         println!("result={:#?}", result);
         std::process::exit(1);
     }
@@ -247,7 +295,7 @@ pub async fn isearch_main() -> Result<()> {
     enable_raw_mode()?;
     crossterm::execute!(stdout(), crossterm::cursor::Hide)?;
 
-    if let Err(e) = main_loop(client).await {
+    if let Err(e) = main_loop(servers).await {
         println!("Error: {:?}\r", e);
     }
 
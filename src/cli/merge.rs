@@ -0,0 +1,51 @@
+use anyhow::{Context, Result};
+use clap::Parser;
+use std::path::PathBuf;
+use tarpc::context;
+
+use crate::db::Database;
+use crate::merge::{merge, MergeSummary};
+
+/// Merge another history database into this one. Entries already present (same session,
+/// host, dir, command and time) are skipped, so merging the same file twice is harmless.
+#[derive(Parser, Debug)]
+pub struct MergeOptions {
+    /// The database to merge in. Without --into, the running server opens it, so this has to
+    /// be run on the server's host.
+    #[clap(value_name = "OTHER_DB")]
+    other: PathBuf,
+
+    /// Merge into DB directly rather than through the server, e.g. while it's stopped
+    #[clap(value_name = "DB", long)]
+    into: Option<PathBuf>,
+}
+
+pub async fn merge_main(options: MergeOptions) -> Result<()> {
+    let summary = match options.into {
+        Some(into) => {
+            let other = std::fs::canonicalize(&options.other)
+                .with_context(|| format!("Unable to find {}", options.other.display()))?;
+            Database::open(into)?
+                .write(move |con| merge(con, &other))
+                .await?
+        }
+        None => {
+            let other = std::env::current_dir()?.join(options.other);
+            let client = super::connect_admin().await?;
+            client.merge(context::current(), other).await??
+        }
+    };
+    print_summary(&summary);
+    Ok(())
+}
+
+fn print_summary(summary: &MergeSummary) {
+    println!(
+        "{} entries: {} merged, {} already present",
+        summary.examined, summary.inserted, summary.duplicates
+    );
+    println!(
+        "{} new commands, {} new places",
+        summary.new_commands, summary.new_places
+    );
+}
@@ -1,10 +1,79 @@
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
+mod backup;
+mod control;
 mod eval;
+mod federation;
 mod isearch;
+mod merge;
+mod mv_dir;
+mod promote;
 mod query;
+mod send;
 mod server;
+mod stats;
+mod status;
+mod tag;
+use std::time::Duration;
+use tarpc::{client, tokio_serde::formats::Bincode};
 use tracing_appender::non_blocking::WorkerGuard;
 
+use crate::local::{query_socket_path, LOCAL_SERVER};
+use crate::tcp::HistoryQueryServiceClient;
+
+/// How long to wait for the primary before falling back to the replica
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Connect to the query server named by the environment set up by `history --eval`, or to its
+/// replica if the server is down and the shell was set up with one
+async fn connect() -> Result<HistoryQueryServiceClient> {
+    let server = crate::HISTORY_SERVER
+        .as_ref()
+        .context("Unable to access environment variable '__history_server'")
+        .context("Did you forget to 'eval \"$(history --eval <server-name>)\"' in your .bashrc?")?;
+    let replica = match crate::HISTORY_REPLICA.as_ref() {
+        Ok(replica) => replica,
+        Err(_) => return connect_to(server).await,
+    };
+    let primary = tokio::time::timeout(CONNECT_TIMEOUT, connect_to(server))
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("Timed out connecting to history server {}", server)));
+    match primary {
+        Ok(client) => Ok(client),
+        Err(e) => {
+            tracing::warn!("{:#}. Using replica {}", e, replica);
+            connect_to(replica).await
+        }
+    }
+}
+
+/// Connect to the server on this host over its local socket, the only way admin RPCs are taken
+async fn connect_admin() -> Result<HistoryQueryServiceClient> {
+    connect_to(LOCAL_SERVER)
+        .await
+        .context("Admin commands have to be run on the server's host, as the user running it")
+}
+
+async fn connect_to(server: &str) -> Result<HistoryQueryServiceClient> {
+    if server == LOCAL_SERVER {
+        let path = query_socket_path()?;
+        let stream = tokio::net::UnixStream::connect(&path)
+            .await
+            .with_context(|| {
+                format!("Unable to connect to history server at {}", path.display())
+            })?;
+        let transport = tarpc::serde_transport::Transport::from((stream, Bincode::default()));
+        return Ok(HistoryQueryServiceClient::new(client::Config::default(), transport).spawn());
+    }
+    let transport = tarpc::serde_transport::tcp::connect(
+        format!("{}:{}", server, crate::HISTORY_PORT),
+        Bincode::default,
+    )
+    .await
+    .with_context(|| format!("Unable to connect to history server {}", server))?;
+
+    Ok(HistoryQueryServiceClient::new(client::Config::default(), transport).spawn())
+}
+
 pub fn register_tracing(daemonized: bool) -> Result<Option<WorkerGuard>> {
     if daemonized {
         let file_appender =
@@ -35,4 +104,5 @@ pub fn register_tracing(daemonized: bool) -> Result<Option<WorkerGuard>> {
 
 pub use isearch::*;
 pub use query::*;
+pub use send::*;
 pub use server::*;
@@ -0,0 +1,72 @@
+use anyhow::Result;
+use clap::Parser;
+use std::path::{Component, Path, PathBuf};
+use tarpc::context;
+
+use crate::tcp::HistoryQueryServiceClient;
+
+/// After moving or renaming a directory, move its history along with it, so that `--in` and
+/// ctrl-r find it under the new name. Covers OLD and every directory below it.
+#[derive(Parser, Debug)]
+pub struct MvDirOptions {
+    #[clap(value_name = "OLD")]
+    old: PathBuf,
+
+    #[clap(value_name = "NEW")]
+    new: PathBuf,
+
+    /// Move the history from host HOSTNAME rather than this one, or from every host if no
+    /// HOSTNAME
+    #[clap(value_name = "[HOSTNAME]", long)]
+    host: Option<Option<String>>,
+
+    /// Show what would be moved, without moving it
+    #[clap(long)]
+    dry_run: bool,
+}
+
+/// `dir` as an absolute path, without needing it to exist, as OLD no longer does
+fn absolute(dir: &Path) -> String {
+    let mut path = PathBuf::from(crate::CWD.as_str());
+    for component in dir.components() {
+        match component {
+            Component::RootDir => path = PathBuf::from("/"),
+            Component::ParentDir => {
+                path.pop();
+            }
+            Component::Normal(x) => path.push(x),
+            Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+    path.display().to_string()
+}
+
+pub async fn mv_dir_main(client: HistoryQueryServiceClient, options: MvDirOptions) -> Result<()> {
+    let (old, new) = (absolute(&options.old), absolute(&options.new));
+    let host = match options.host {
+        None => Some(crate::MYHOSTNAME.clone()),
+        Some(host) => host,
+    };
+    let summary = client
+        .move_dir(
+            context::current(),
+            old.clone(),
+            new.clone(),
+            host,
+            options.dry_run,
+        )
+        .await??;
+    println!(
+        "{} {} to {}: {} dirs with {} entries, {} of them merged into dirs already there",
+        match options.dry_run {
+            true => "Would move",
+            false => "Moved",
+        },
+        old,
+        new,
+        summary.places,
+        summary.entries,
+        summary.merged
+    );
+    Ok(())
+}
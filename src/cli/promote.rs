@@ -0,0 +1,16 @@
+use anyhow::Result;
+use clap::Parser;
+use tarpc::context;
+
+/// Promote a replica to primary: it stops copying from the primary and starts accepting
+/// history. Run it on the replica's host, as the user running the replica. Point shells at it
+/// with `history --eval <REPLICA>` afterwards.
+#[derive(Parser, Debug)]
+pub struct PromoteOptions {}
+
+pub async fn promote_main(_options: PromoteOptions) -> Result<()> {
+    let client = super::connect_admin().await?;
+    client.promote(context::current()).await??;
+    println!("{} is now the primary", *crate::MYHOSTNAME);
+    Ok(())
+}
@@ -1,24 +1,47 @@
 use anyhow::{Context, Result};
 use chrono::prelude::*;
-use clap::{AppSettings, Parser};
-use git_version::git_version;
-use stybulate::{Cell, Headers, Style, Table};
-use tarpc::{client, context, tokio_serde::formats::Bincode};
+use clap::{AppSettings, Parser, Subcommand};
+use crossterm::style::Attribute;
+use std::io::IsTerminal;
+use stybulate::{AsciiEscapedString, Cell, Headers, Style, Table};
+use tarpc::context;
 
-use crate::tcp::HistoryQueryServiceClient;
+use super::backup::{backup_main, BackupOptions};
+use super::control::{server_control_main, ServerControlOptions};
+use super::federation::connect_servers;
+use super::merge::{merge_main, MergeOptions};
+use super::mv_dir::{mv_dir_main, MvDirOptions};
+use super::promote::{promote_main, PromoteOptions};
+use super::stats::{stats_main, StatsOptions};
+use super::status::status_main;
+use super::tag::{note_main, tag_main, untag_main, NoteOptions, TagOptions, UntagOptions};
+use crate::config::Config;
+use crate::repo::{find_repo, repo_identity};
+use crate::tcp::{Grouping, HistoryQueryServiceClient, Query, QueryResultRow, MAX_PAGE_SIZE};
 
 /// Search shell command history
 #[derive(Parser, Debug)]
-#[clap(author, version = git_version!(fallback="0.1"), about, long_about = None)]
+#[clap(author, version = crate::VERSION, about, long_about = None)]
 #[clap(global_setting(AppSettings::DeriveDisplayOrder))]
+#[clap(args_conflicts_with_subcommands = true)]
 pub struct QueryClientOptions {
-    /// Show only N rows
-    #[clap(value_name = "N", short = 'n', long = "--limit", default_value = "25")]
+    #[clap(subcommand)]
+    subcommand: Option<Commands>,
+
+    /// Show only N rows, or every row if N is -1
+    #[clap(
+        value_name = "N",
+        short = 'n',
+        long = "--limit",
+        default_value = "25",
+        allow_hyphen_values = true
+    )]
     limit: i32,
 
-    /// Show only entries from session T.
+    /// Show only entries from the current shell, or from session T. T can also be a
+    /// /dev/pts number, as shown in the tty column.
     #[clap(value_name = "T", short = 't', long = "--tty")]
-    session: Option<Option<i32>>,
+    session: Option<Option<String>>,
 
     /// Show only entries since the specified date.
     #[clap(value_name = "TIME", short = 's', long)]
@@ -29,10 +52,16 @@ pub struct QueryClientOptions {
     until: Option<String>,
 
     /// Show only rows with exit status X. Can be 'error' to find all nonzero.
-    #[clap(value_name = "X", short = 'x', long)]
+    #[clap(
+        value_name = "X",
+        short = 'x',
+        long = "--exit-status",
+        alias = "status"
+    )]
     status: Option<Option<String>>,
 
-    /// Reverse sort order of results.
+    /// Reverse sort order of results. Rows are printed as they arrive from the server, rather
+    /// than all at once, which is much faster for big exports.
     #[clap(long = "--desc")]
     desc: bool,
 
@@ -45,6 +74,12 @@ pub struct QueryClientOptions {
     #[clap(value_name = "[DIR]", long = "--at")]
     at: Option<Option<String>>,
 
+    /// Find only entries run in the git repository the current dir is in, or the one DIR is
+    /// in, in any checkout of it on any host. Also takes the identity of a repository, like
+    /// github.com/me/x. Dirs are shown relative to the checkout.
+    #[clap(value_name = "[DIR|REPO]", long = "--repo")]
+    repo: Option<Option<String>>,
+
     /// Print the host column and show all hosts if no HOSTNAME
     /// or find only entries from host HOSTNAME.
     #[clap(value_name = "[HOSTNAME]", long)]
@@ -58,17 +93,89 @@ pub struct QueryClientOptions {
     #[clap(long = "--no-header")]
     nh: bool,
 
+    /// Show every execution, rather than only the latest run of each command in each directory.
+    #[clap(long = "--all", conflicts_with = "unique-cmd")]
+    all: bool,
+
+    /// Show only the latest run of each command, regardless of directory.
+    #[clap(long = "--unique-cmd")]
+    unique_cmd: bool,
+
+    /// Also show the N commands run after each match in the same shell.
+    #[clap(value_name = "N", short = 'A', long = "--after-context")]
+    after_context: Option<u32>,
+
+    /// Also show the N commands run before each match in the same shell.
+    #[clap(value_name = "N", short = 'B', long = "--before-context")]
+    before_context: Option<u32>,
+
+    /// Also show the N commands run before and after each match in the same shell.
+    #[clap(value_name = "N", short = 'C', long = "--context")]
+    context: Option<u32>,
+
+    /// Show only entries run with this context, like branch=main or venv=/path/to/venv. Can be
+    /// given more than once. See [context] in the config file for what's recorded.
+    #[clap(
+        value_name = "KEY=VALUE",
+        long = "--ctx",
+        multiple_occurrences = true,
+        value_parser = parse_key_value
+    )]
+    ctx: Vec<(String, String)>,
+
+    /// Show only entries tagged TAG, with `history tag`. Can be given more than once.
+    #[clap(value_name = "TAG", long = "--tag", multiple_occurrences = true)]
+    tag: Vec<String>,
+
+    /// Print the id column, which names entries for `history tag` and `history note`.
+    #[clap(long = "--ids")]
+    ids: bool,
+
+    /// Also search the servers listed under [federation] in the config file, labeling each
+    /// row with the server it came from.
+    #[clap(long = "--federated")]
+    federated: bool,
+
+    /// Show the server's version, uptime, database size and ingestion counters.
+    #[clap(long = "--server-status")]
+    server_status: bool,
+
     /// Generate eval string for bash (use eval "$(history --eval <ADDR>)"). Supply server addr,
     /// like 127.0.0.1 if you want to run the server locally, or remote addr/ip if you want to
     /// centralize the history.
     #[clap(long = "--eval", name = "SERVER_ADDR")]
     eval: Option<String>,
 
+    /// With --eval, also run a replica of the server at REPLICA_ADDR, and query it whenever
+    /// the server is down.
+    #[clap(long = "--replica", name = "REPLICA_ADDR", requires = "SERVER_ADDR")]
+    replica: Option<String>,
+
     /// Search history for commands containing this fragment.
     #[clap()]
     command: Option<String>,
 }
 
+fn parse_key_value(s: &str) -> Result<(String, String)> {
+    let (key, value) = s
+        .split_once('=')
+        .with_context(|| format!("Expected KEY=VALUE, not {:?}", s))?;
+    Ok((key.to_string(), value.to_string()))
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    Stats(StatsOptions),
+    Promote(PromoteOptions),
+    Merge(MergeOptions),
+    Backup(BackupOptions),
+    Server(ServerControlOptions),
+    Tag(TagOptions),
+    Untag(UntagOptions),
+    Note(NoteOptions),
+    MvDir(MvDirOptions),
+}
+
 pub async fn query_client_main() -> Result<()> {
     let options = QueryClientOptions::parse();
 
@@ -79,37 +186,86 @@ pub async fn query_client_main() -> Result<()> {
         if !shell.ends_with("bash") {
             anyhow::bail!("Sorry, history only supports the bash shell. I see from $SHELL you're running from {:?}", shell);
         }
-        return crate::cli::eval::show_bash_eval_string(server_addr).await;
+        return crate::cli::eval::show_bash_eval_string(server_addr, options.replica).await;
+    }
+    let subcommand = match options.subcommand {
+        Some(Commands::Promote(promote_options)) => return promote_main(promote_options).await,
+        Some(Commands::Merge(merge_options)) => return merge_main(merge_options).await,
+        Some(Commands::Backup(backup_options)) => return backup_main(backup_options).await,
+        Some(Commands::Server(control_options)) => {
+            return server_control_main(control_options).await
+        }
+        x => x,
+    };
+    if options.server_status {
+        return status_main(super::connect().await?).await;
+    }
+    match subcommand {
+        Some(Commands::Stats(stats_options)) => {
+            return stats_main(super::connect().await?, stats_options).await
+        }
+        Some(Commands::Tag(tag_options)) => {
+            return tag_main(super::connect().await?, tag_options).await
+        }
+        Some(Commands::Untag(untag_options)) => {
+            return untag_main(super::connect().await?, untag_options).await
+        }
+        Some(Commands::Note(note_options)) => {
+            return note_main(super::connect().await?, note_options).await
+        }
+        Some(Commands::MvDir(mv_dir_options)) => {
+            return mv_dir_main(super::connect().await?, mv_dir_options).await
+        }
+        Some(
+            Commands::Promote(_) | Commands::Merge(_) | Commands::Backup(_) | Commands::Server(_),
+        )
+        | None => {}
     }
-    let server = crate::HISTORY_SERVER
-        .as_ref()
-        .context("Unable to access environment variable '__history_server'")
-        .context("Did you forget to 'eval \"$(history --eval <server-name>)\"' in your .bashrc?")?;
-
-    let transport = tarpc::serde_transport::tcp::connect(
-        format!("{}:{}", server, crate::HISTORY_PORT),
-        Bincode::default,
-    )
-    .await?;
 
     let now = Utc::now();
-    let client = HistoryQueryServiceClient::new(client::Config::default(), transport).spawn();
-    let mysession = crate::util::getsession().context("Unable to get current tty session")?;
     let parse_time = |x: Option<&String>| -> Result<Option<i64>> {
         match x {
             Some(s) => Ok(Some(crate::util::parse_time(s)?)),
             None => Ok(None),
         }
     };
-    let display_host_column = options.host == Some(None);
+    let display_id_column = options.ids;
+    let display_server_column = options.federated;
+    // checkouts of a repository are usually spread across hosts
+    let all_hosts =
+        options.host == Some(None) || (options.host.is_none() && options.repo.is_some());
+    let display_host_column = all_hosts;
+    let repo_relative = options.repo.is_some();
     let display_tty_column = options.session.is_none();
     let display_dir_column = options.at.is_none();
+    let grouping = match (options.all, options.unique_cmd) {
+        (true, _) => Grouping::None,
+        (false, true) => Grouping::Command,
+        (false, false) => Grouping::CommandPlace,
+    };
+    let display_count_column = grouping != Grouping::None;
+    let (session, tty) = match options.session {
+        None => (None, None),
+        Some(Some(t)) => match t.parse::<i32>() {
+            Ok(tty) => (None, Some(tty)),
+            Err(_) => (Some(t), None),
+        },
+        Some(None) => match crate::SESSION.as_ref() {
+            Ok(s) => (Some(s.clone()), None),
+            // shells set up before sessions had unique ids only know their tty
+            Err(_) => (
+                None,
+                Some(crate::util::getsession().context("Unable to get current tty session")?),
+            ),
+        },
+    };
 
     let query = crate::tcp::Query {
         // options.host == None => restrict query to this host
         // options.host == Some(None) => all hosts
         // options.host == Some(Some(s)) ==> restrict query to host s
         host: match options.host {
+            _ if all_hosts => None,
             None => Some(crate::MYHOSTNAME.clone()),
             Some(None) => None,
             Some(Some(s)) => Some(s),
@@ -122,71 +278,333 @@ pub async fn query_client_main() -> Result<()> {
         atdir: options
             .at
             .map(|x| x.unwrap_or_else(|| crate::CWD.to_string())),
-        session: options.session.map(|x| x.unwrap_or(mysession)),
+        session,
+        tty,
         status: options.status.map(|x| x.unwrap_or("error".to_string())),
         since: parse_time(options.since.as_ref())?,
         until: parse_time(options.until.as_ref())?,
-        desc: options.desc,
         limit: options.limit,
+        cursor: None,
+        grouping,
+        before: options.before_context.or(options.context).unwrap_or(0),
+        after: options.after_context.or(options.context).unwrap_or(0),
+        context: match options.repo {
+            Some(repo) => options
+                .ctx
+                .into_iter()
+                .chain([repo_context(repo.as_deref())?])
+                .collect(),
+            None => options.ctx,
+        },
+        tags: options.tag,
     };
+    let show_context = query.before > 0 || query.after > 0;
+    let is_terminal = std::io::stdout().is_terminal();
+    let highlight = show_context && is_terminal;
     tracing::debug!("{:#?}", query);
 
-    let out: Vec<Vec<Cell>> = client
-        .query(context::current(), query)
-        .await??
-        .into_iter()
-        .map(|row| {
-            let dt = DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(row.time, 0), Utc);
-            let local = DateTime::<Local>::from(dt);
-            let date = if dt.date() == now.date() {
-                Cell::from(&local.format("%-I:%M%p").to_string())
-            } else {
-                Cell::from(&local.format("%m/%d").to_string())
+    let format = |row: &QueryResultRow, origin: &str, hit: bool| -> Vec<Cell> {
+        let dt = DateTime::<Utc>::from_utc(
+            NaiveDateTime::from_timestamp_opt(row.time, 0).unwrap_or_default(),
+            Utc,
+        );
+        let local = DateTime::<Local>::from(dt);
+        let date = if dt.date_naive() == now.date_naive() {
+            Cell::from(&local.format("%-I:%M%p").to_string())
+        } else {
+            Cell::from(&local.format("%m/%d").to_string())
+        };
+        let mut fmtrow = vec![date];
+        if display_id_column {
+            fmtrow.push(Cell::from(&row.id.to_string()));
+        }
+        if display_server_column {
+            fmtrow.push(Cell::from(origin));
+        }
+        if display_host_column {
+            fmtrow.push(Cell::from(&remove_zero_width_graphemes(&row.host)));
+        }
+        if display_tty_column {
+            fmtrow.push(match row.tty {
+                Some(tty) => Cell::Int(tty),
+                None => Cell::from(""),
+            });
+        }
+        if display_dir_column {
+            let dir = match (&row.repo, repo_relative) {
+                (Some(root), true) => relative_dir(&row.dir, root),
+                _ => &row.dir,
             };
-            let mut fmtrow = vec![date];
-            if display_host_column {
-                fmtrow.push(Cell::from(&remove_zero_width_graphemes(&row.host)));
-            }
-            if display_tty_column {
-                fmtrow.push(Cell::Int(row.session));
+            fmtrow.push(Cell::from(&remove_zero_width_graphemes(dir)));
+        }
+
+        if display_count_column {
+            fmtrow.push(Cell::Int(row.count as i32));
+        }
+
+        let mut argv = remove_zero_width_graphemes(&row.argv);
+        if hit && highlight {
+            argv = format!("{}{}{}", Attribute::Reverse, argv, Attribute::Reset);
+        }
+        if let Some(marker) = annotation_marker(&row.tags, row.note.as_deref()) {
+            let marker = remove_zero_width_graphemes(&marker);
+            argv = match is_terminal {
+                true => format!("{}  {}{}{}", argv, Attribute::Dim, marker, Attribute::Reset),
+                false => format!("{}  {}", argv, marker),
+            };
+        }
+        fmtrow.push(Cell::Text(Box::new(AsciiEscapedString::from(&argv))));
+        fmtrow
+    };
+
+    let headers = if options.nh {
+        None
+    } else {
+        let mut keys = vec!["time"];
+        if display_id_column {
+            keys.push("id");
+        }
+        if display_server_column {
+            keys.push("server");
+        }
+        if display_host_column {
+            keys.push("host");
+        }
+        if display_tty_column {
+            keys.push("tty");
+        }
+        if display_dir_column {
+            keys.push("dir");
+        }
+        if display_count_column {
+            keys.push("runs");
+        }
+        keys.push("cmd");
+        Some(keys)
+    };
+
+    let mut n_matches = 0;
+    let mut format_page = |rows: &[(&str, QueryResultRow)]| -> Vec<Vec<Cell>> {
+        let mut out: Vec<Vec<Cell>> = Vec::new();
+        for (origin, row) in rows {
+            if show_context && n_matches > 0 {
+                // like grep, separate each match and its context from the next
+                let mut separator = vec![Cell::from("--")];
+                separator.resize_with(format(row, origin, false).len(), || Cell::from(""));
+                out.push(separator);
             }
-            if display_dir_column {
-                fmtrow.push(Cell::from(&remove_zero_width_graphemes(&row.dir)));
+            out.extend(row.before.iter().map(|x| format(x, origin, false)));
+            out.push(format(row, origin, true));
+            out.extend(row.after.iter().map(|x| format(x, origin, false)));
+            n_matches += 1;
+        }
+        out
+    };
+
+    let mut widths = Vec::new();
+    if options.federated {
+        let own = crate::HISTORY_SERVER.as_deref().unwrap_or_default();
+        let config = Config::load()?;
+        // our own server is just one more, so that its being down only costs its results
+        let servers = connect_servers(&config.federation.servers, own).await;
+        let names: Vec<String> = servers.iter().map(|s| s.name.clone()).collect();
+        let clients = servers.into_iter().map(|s| s.client);
+
+        let results =
+            futures_util::future::join_all(names.iter().map(|x| x.as_str()).zip(clients).map(
+                |(name, client)| {
+                    let query = query.clone();
+                    async move {
+                        let mut rows = Vec::new();
+                        let result = match client {
+                            Ok(client) => {
+                                fetch_pages(&client, query, options.limit, |page, _| {
+                                    rows.extend(page.into_iter().map(|row| (name, row)))
+                                })
+                                .await
+                            }
+                            Err(e) => Err(e),
+                        };
+                        (name, result.map(|_| rows))
+                    }
+                },
+            ))
+            .await;
+
+        let mut merged = Vec::new();
+        let mut reachable = 0;
+        for (name, result) in results {
+            match result {
+                Ok(rows) => {
+                    reachable += 1;
+                    merged.extend(rows);
+                }
+                Err(e) => tracing::warn!("Skipping history server {}: {:#}", name, e),
             }
+        }
+        if reachable == 0 {
+            anyhow::bail!("None of the history servers could be reached");
+        }
+        merged.sort_by_key(|(_, row)| std::cmp::Reverse(row.time));
+        if options.limit >= 0 {
+            merged.truncate(options.limit as usize);
+        }
+        if !options.desc {
+            merged.reverse();
+        }
+        print_table(format_page(&merged), headers.as_ref(), &mut widths);
+        return Ok(());
+    }
 
-            fmtrow.push(Cell::from(&remove_zero_width_graphemes(&row.argv)));
-            fmtrow
+    // The server sends results newest first, a page at a time. With --desc that's the order
+    // we want, so print each page as soon as it arrives. Otherwise, we need all of them before
+    // we can print the oldest.
+    let client = super::connect().await?;
+    let mut collected = Vec::new();
+    fetch_pages(&client, query, options.limit, |page, is_first_page| {
+        let page: Vec<_> = page.into_iter().map(|row| ("", row)).collect();
+        if options.desc {
+            print_table(
+                format_page(&page),
+                headers.as_ref().filter(|_| is_first_page),
+                &mut widths,
+            );
+        } else {
+            collected.extend(page);
+        }
+    })
+    .await?;
+    if !options.desc {
+        collected.reverse();
+        print_table(format_page(&collected), headers.as_ref(), &mut widths);
+    }
+    Ok(())
+}
+
+/// Run `query` a page at a time until `limit` rows (all of them, if negative) have come back
+/// or there are no more, passing each page (newest first) to `on_page` along with whether it's
+/// the first
+async fn fetch_pages(
+    client: &HistoryQueryServiceClient,
+    mut query: Query,
+    limit: i32,
+    mut on_page: impl FnMut(Vec<QueryResultRow>, bool),
+) -> Result<()> {
+    let mut remaining = match limit {
+        n if n < 0 => None,
+        n => Some(n),
+    };
+    while remaining != Some(0) {
+        query.limit = remaining.map_or(MAX_PAGE_SIZE, |n| n.min(MAX_PAGE_SIZE));
+        let page = client.query(context::current(), query.clone()).await??;
+        remaining = remaining.map(|n| (n - page.rows.len() as i32).max(0));
+        on_page(page.rows, query.cursor.is_none());
+        match page.next {
+            Some(cursor) => query.cursor = Some(cursor),
+            None => break,
+        }
+    }
+    Ok(())
+}
+
+/// Print rows as a table. `widths` carries the widths of the columns printed so far, so that
+/// tables printed one after another line up unless a later one has a wider cell.
+fn print_table(rows: Vec<Vec<Cell>>, headers: Option<&Vec<&str>>, widths: &mut Vec<usize>) {
+    use unicode_width::UnicodeWidthStr;
+
+    if rows.is_empty() && headers.is_none() {
+        return;
+    }
+    let as_text = |cell: &Cell| match cell {
+        Cell::Int(i) => (i.to_string(), i.to_string()),
+        Cell::Float(f) => (f.to_string(), f.to_string()),
+        Cell::Text(t) => (t.to_string(), t.unstyle()),
+    };
+    for (i, width) in headers
+        .iter()
+        // stybulate leaves at least two spaces of padding around headers
+        .flat_map(|h| h.iter().map(|x| x.width() + 2).enumerate())
+        .chain(
+            rows.iter()
+                .flat_map(|row| row.iter().map(|cell| as_text(cell).1.width()).enumerate()),
+        )
+    {
+        if widths.len() <= i {
+            widths.resize(i + 1, 0);
+        }
+        widths[i] = widths[i].max(width);
+    }
+
+    let padded = rows
+        .iter()
+        .map(|row| {
+            row.iter()
+                .enumerate()
+                .map(|(i, cell)| {
+                    let (styled, plain) = as_text(cell);
+                    let pad = match i + 1 == row.len() {
+                        // no trailing whitespace after the command
+                        true => String::new(),
+                        false => " ".repeat(widths[i] - plain.width()),
+                    };
+                    Cell::Text(Box::new(AsciiEscapedString::from(&match cell {
+                        // keep numbers right-aligned
+                        Cell::Int(_) | Cell::Float(_) => format!("{}{}", pad, styled),
+                        Cell::Text(_) => format!("{}{}", styled, pad),
+                    })))
+                })
+                .collect()
         })
         .collect();
+    let headers = headers.map(|h| Headers::from(h.clone()));
+    println!("{}", Table::new(Style::Plain, padded, headers).tabulate());
+}
 
-    let result = Table::new(
-        Style::Plain,
-        out,
-        if options.nh {
-            None
-        } else {
-            let mut keys = vec!["time"];
-            if display_host_column {
-                keys.push("host");
-            }
-            if display_tty_column {
-                keys.push("tty");
-            }
-            if display_dir_column {
-                keys.push("dir");
-            }
-            keys.push("cmd");
-
-            Some(Headers::from(keys))
+/// The context that identifies the repository named by `--repo`: its remote, so that every
+/// checkout of it matches, or for a checkout without one, where it is
+fn repo_context(arg: Option<&str>) -> Result<(String, String)> {
+    let dir = match arg {
+        None => std::path::PathBuf::from(crate::CWD.as_str()),
+        Some(arg) => match std::fs::canonicalize(arg) {
+            Ok(dir) if dir.is_dir() => dir,
+            // not a directory here, so the identity of a repository
+            _ => return Ok(("repo_id".to_string(), repo_identity(arg))),
         },
-    )
-    .tabulate();
-    println!("{}", result);
-    Ok(())
+    };
+    let repo =
+        find_repo(&dir).with_context(|| format!("{} isn't in a git repository", dir.display()))?;
+    Ok(match repo.remote {
+        Some(url) => ("repo_id".to_string(), repo_identity(&url)),
+        None => ("repo".to_string(), repo.root.display().to_string()),
+    })
+}
+
+/// `dir` relative to the checkout at `root`, or all of it if it isn't inside
+fn relative_dir<'a>(dir: &'a str, root: &str) -> &'a str {
+    match dir.strip_prefix(root) {
+        Some("") => ".",
+        Some(rest) if rest.starts_with('/') => &rest[1..],
+        _ => dir,
+    }
+}
+
+/// What's shown after a command that's been tagged or has a note, like
+/// `# [deploy cert] this is how to rotate the cert`
+pub(super) fn annotation_marker(tags: &[String], note: Option<&str>) -> Option<String> {
+    let tags = match tags.is_empty() {
+        true => None,
+        false => Some(format!("[{}]", tags.join(" "))),
+    };
+    match (tags, note) {
+        (None, None) => None,
+        (Some(tags), None) => Some(format!("# {}", tags)),
+        (None, Some(note)) => Some(format!("# {}", note)),
+        (Some(tags), Some(note)) => Some(format!("# {} {}", tags, note)),
+    }
 }
 
 // Fix for https://github.com/guigui64/stybulate/issues/18
-fn remove_zero_width_graphemes(s: &str) -> String {
+pub(super) fn remove_zero_width_graphemes(s: &str) -> String {
     use unicode_segmentation::UnicodeSegmentation;
     use unicode_width::UnicodeWidthStr;
 
@@ -0,0 +1,61 @@
+use anyhow::{Context, Result};
+use std::io::Read;
+use std::os::unix::net::UnixDatagram;
+use std::time::{Duration, Instant, SystemTime};
+use tarpc::context;
+
+use crate::local::ingest_socket_path;
+use crate::udp::{deserialize, RpcMessage};
+
+/// How long a reliable send keeps trying before the entry is given up on
+const SEND_DEADLINE: Duration = Duration::from_secs(60);
+const RETRY_INTERVAL: Duration = Duration::from_secs(2);
+const INSERT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Forward one entry, formatted like the UDP datagram, from stdin to the server. The prompt
+/// hook uses this when bash can't send the entry itself:
+///
+/// - With `__history_reliable` set, the entry goes over the `insert` RPC, retrying until the
+///   server acknowledges it. The hook does this for entries too big for a datagram, and for
+///   all of them in reliable mode. It runs in the background, so the prompt doesn't wait.
+/// - Otherwise it's sent to the local server's ingestion socket, since bash can't write to a
+///   Unix socket. Like a UDP send, this never waits on the server: if it isn't running or is
+///   backed up, the entry is dropped.
+pub fn send_main() -> Result<()> {
+    let mut buf = Vec::new();
+    std::io::stdin().read_to_end(&mut buf)?;
+    if std::env::var_os("__history_reliable").is_none() {
+        let socket = UnixDatagram::unbound()?;
+        socket.set_nonblocking(true)?;
+        let _ = socket.send_to(&buf, ingest_socket_path()?);
+        return Ok(());
+    }
+
+    let msg = deserialize(&buf)?;
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("Unable to construct tokio runtime")
+        .block_on(send_reliably(msg))
+}
+
+async fn send_reliably(msg: RpcMessage) -> Result<()> {
+    let deadline = Instant::now() + SEND_DEADLINE;
+    loop {
+        let result = async {
+            let client = super::connect().await?;
+            let mut ctx = context::current();
+            ctx.deadline = SystemTime::now() + INSERT_TIMEOUT;
+            client.insert(ctx, msg.clone()).await??;
+            Ok::<_, anyhow::Error>(())
+        }
+        .await;
+        match result {
+            Ok(()) => return Ok(()),
+            Err(e) if Instant::now() + RETRY_INTERVAL > deadline => {
+                return Err(e).context("Giving up on sending history")
+            }
+            Err(_) => tokio::time::sleep(RETRY_INTERVAL).await,
+        }
+    }
+}
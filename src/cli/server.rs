@@ -1,15 +1,35 @@
-use std::{process::exit, sync::Arc};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
 
 use crate::{
-    monitor::server_monitor_log_forever, schema::create_schema, tcp::HistoryQueryServer,
-    udp::InsertServer,
+    backup::snapshot_forever,
+    db::Database,
+    listen::{Allowlist, Cidr, Listen},
+    local::LOCAL_SERVER,
+    metrics::{serve_metrics_forever, Metrics},
+    monitor::server_monitor_log_forever,
+    pidfile::{pidfile_path, PidFile},
+    replication::Replica,
+    retention::{retention_forever, RetentionPolicy},
+    tcp::HistoryQueryServer,
+    udp::{IngestStats, InsertServer},
 };
-use anyhow::Result;
+use anyhow::{Context, Result};
 use clap::Parser;
-use rusqlite::Connection;
-use tokio::sync::Mutex;
+use tarpc::context;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+
+use super::{connect_to, register_tracing};
 
-use super::register_tracing;
+/// How long shutdown waits for queued history and in-flight queries
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a server that holds the pidfile gets to start answering queries
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(Parser, Debug)]
 pub struct ServerOptions {
@@ -17,6 +37,70 @@ pub struct ServerOptions {
     #[clap(long)]
     daemonize: bool,
 
+    /// Take history and queries only from this user on this machine, over Unix sockets under
+    /// $XDG_RUNTIME_DIR/history, rather than from the network on port 29080
+    #[clap(long, conflicts_with_all = &["bind", "allow"])]
+    local: bool,
+
+    /// Listen for history and queries on ADDR only, such as a cluster-internal interface,
+    /// rather than on every interface
+    #[clap(value_name = "ADDR", long, default_value = "0.0.0.0")]
+    bind: IpAddr,
+
+    /// Take history and queries only from peers in CIDR, like 10.0.0.0/8. Can be given more
+    /// than once. Without it, any peer is allowed.
+    #[clap(value_name = "CIDR", long, multiple_occurrences = true)]
+    allow: Vec<Cidr>,
+
+    /// Serve Prometheus metrics over HTTP at ADDR, e.g. 127.0.0.1:29081
+    #[clap(value_name = "ADDR", long)]
+    metrics_addr: Option<SocketAddr>,
+
+    /// Run as a replica of the history server at PRIMARY, copying its history as it comes in.
+    /// A replica answers queries but doesn't accept history until it's promoted with
+    /// `history promote`.
+    #[clap(value_name = "PRIMARY", long)]
+    replica_of: Option<String>,
+
+    /// Write a snapshot of the database into DIR every --snapshot-interval hours
+    #[clap(value_name = "DIR", long)]
+    snapshot_dir: Option<PathBuf>,
+
+    /// Hours between snapshots
+    #[clap(
+        value_name = "HOURS",
+        long,
+        default_value = "24",
+        requires = "snapshot-dir"
+    )]
+    snapshot_interval: u64,
+
+    /// Number of snapshots to keep. Older ones are deleted.
+    #[clap(value_name = "N", long, default_value = "7", requires = "snapshot-dir")]
+    snapshot_keep: usize,
+
+    /// Delete entries older than DAYS days
+    #[clap(value_name = "DAYS", long)]
+    retain_days: Option<u32>,
+
+    /// Keep only the latest N runs of each command in each directory
+    #[clap(value_name = "N", long)]
+    retain_runs: Option<u32>,
+
+    /// Delete the oldest entries until the database fits in MIB mebibytes (units of 1024 * 1024
+    /// bytes)
+    #[clap(value_name = "MIB", long)]
+    max_size_mib: Option<u64>,
+
+    /// VACUUM the database after applying the retention policy, to return the freed space to
+    /// the filesystem
+    #[clap(long)]
+    vacuum: bool,
+
+    /// Hours between applications of the retention policy
+    #[clap(value_name = "HOURS", long, default_value = "24")]
+    retention_interval: u64,
+
     /// History file (sqlite db)
     #[clap()]
     history: String,
@@ -24,6 +108,10 @@ pub struct ServerOptions {
 
 pub fn server_main() -> Result<()> {
     let options = ServerOptions::parse();
+    let pidfile = match PidFile::acquire(&pidfile_path(options.history.as_ref()))? {
+        Some(pidfile) => pidfile,
+        None => return check_running_server(&options),
+    };
     match options.daemonize {
         true => {
             let stdout = std::fs::OpenOptions::new()
@@ -39,19 +127,70 @@ pub fn server_main() -> Result<()> {
                 .stdout(stdout)
                 .stderr(stderr);
             match daemonize.start() {
-                Ok(_) => server_main_impl(options, true),
+                Ok(_) => server_main_impl(options, pidfile, true),
                 Err(e) => {
                     eprintln!("Error, {}", e);
                     Ok(())
                 }
             }
         }
-        false => server_main_impl(options, false),
+        false => server_main_impl(options, pidfile, false),
+    }
+}
+
+/// Every new shell that evals the hook tries to start the server, so finding it already running
+/// is the usual case, and not worth a word. Finding the pidfile locked by a server that doesn't
+/// answer, after giving it time to start, is.
+fn check_running_server(options: &ServerOptions) -> Result<()> {
+    let mut addrs = vec![LOCAL_SERVER.to_string()];
+    if !options.local {
+        let ip = match (options.bind, options.bind.is_unspecified()) {
+            (IpAddr::V4(_), true) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            (IpAddr::V6(_), true) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            (ip, false) => ip,
+        };
+        addrs.push(match ip {
+            IpAddr::V4(ip) => ip.to_string(),
+            IpAddr::V6(ip) => format!("[{}]", ip),
+        });
     }
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    rt.block_on(async {
+        let mut last_error = None;
+        let answered = tokio::time::timeout(STARTUP_TIMEOUT, async {
+            loop {
+                for addr in &addrs {
+                    let status = async {
+                        connect_to(addr).await?.status(context::current()).await??;
+                        anyhow::Ok(())
+                    };
+                    match status.await {
+                        Ok(()) => return,
+                        Err(e) => last_error = Some(e),
+                    }
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await;
+        answered
+            .map_err(|_| {
+                last_error.unwrap_or_else(|| anyhow::anyhow!("Timed out waiting for status"))
+            })
+            .with_context(|| {
+                format!(
+                    "Another history server holds {}, but isn't answering",
+                    pidfile_path(options.history.as_ref()).display()
+                )
+            })
+    })
 }
 
-fn server_main_impl(options: ServerOptions, daemonized: bool) -> Result<()> {
+fn server_main_impl(options: ServerOptions, mut pidfile: PidFile, daemonized: bool) -> Result<()> {
     let _guard = register_tracing(daemonized)?;
+    pidfile.write_pid()?;
     let rt = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()
@@ -64,30 +203,133 @@ fn server_main_impl(options: ServerOptions, daemonized: bool) -> Result<()> {
             std::process::id(),
             options.history,
         );
-        let con = Connection::open(options.history)?;
-        create_schema(&con)?;
-        let con = Arc::new(Mutex::new(con));
-        let udp_server = InsertServer::new(con.clone()).await?;
-        let tcp_server = HistoryQueryServer::new(con.clone());
-
-        let mon = tokio::spawn(async { server_monitor_log_forever().await });
-        let udp = tokio::spawn(async move { udp_server.run().await });
-        let tcp = tokio::spawn(async move { tcp_server.run().await });
-
-        tokio::select! {
-            r = mon => {
-                r?
-            }
-            r = udp => {
-                r?
-            }
-            r = tcp => {
-                r?
+        let db = Database::open(&options.history)?;
+        let listen = match options.local {
+            true => Listen::Local,
+            false => Listen::Network {
+                bind: options.bind,
+                allowlist: Arc::new(Allowlist::new(options.allow)),
             },
-            _ = tokio::signal::ctrl_c() => {
-                exit(1);
-            }
-        }?;
+        };
+        let ingest_stats = Arc::new(IngestStats::default());
+        let replica = options
+            .replica_of
+            .map(|primary| Arc::new(Replica::new(primary)));
+        if let Some(replica) = &replica {
+            replica.resume(&db).await?;
+        }
+        let udp_server = match replica {
+            // bind now, so that a port conflict is reported right away
+            None => Some(InsertServer::new(db.clone(), ingest_stats.clone(), &listen).await?),
+            Some(_) => None,
+        };
+        let metrics = Arc::new(Metrics::new(ingest_stats.clone()));
+        let tcp_server = HistoryQueryServer::new(db.clone(), metrics.clone(), replica.clone());
+        let in_flight = tcp_server.in_flight();
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+        let mut udp = {
+            let (db, ingest_stats, listen) = (db.clone(), ingest_stats.clone(), listen.clone());
+            let replica = replica.clone();
+            let mut shutdown = shutdown_rx;
+            tokio::spawn(async move {
+                let udp_server = match (udp_server, replica) {
+                    (Some(udp_server), _) => udp_server,
+                    (None, Some(replica)) => {
+                        tokio::select! {
+                            _ = replica.run_until_promoted(&db) => {}
+                            _ = shutdown.changed() => return Ok(()),
+                        }
+                        InsertServer::new(db, ingest_stats, &listen).await?
+                    }
+                    (None, None) => unreachable!(),
+                };
+                udp_server.run(shutdown).await
+            })
+        };
+        let mon = tokio::spawn(async { server_monitor_log_forever(ingest_stats).await });
+        let mut tcp = tokio::spawn(async move { tcp_server.run(listen).await });
+        let snapshots = match options.snapshot_dir {
+            Some(dir) => tokio::spawn(snapshot_forever(
+                db.clone(),
+                dir,
+                Duration::from_secs(options.snapshot_interval.max(1) * 60 * 60),
+                options.snapshot_keep.max(1),
+            )),
+            None => tokio::spawn(futures_util::future::pending()),
+        };
+        let policy = RetentionPolicy {
+            max_age_days: options.retain_days,
+            max_runs: options.retain_runs,
+            max_size_bytes: options.max_size_mib.map(|mib| mib * 1024 * 1024),
+            vacuum: options.vacuum,
+        };
+        let retention = match policy.is_empty() {
+            false => tokio::spawn(retention_forever(
+                db.clone(),
+                policy,
+                replica,
+                Duration::from_secs(options.retention_interval.max(1) * 60 * 60),
+            )),
+            true => tokio::spawn(futures_util::future::pending()),
+        };
+        let metrics_addr = options.metrics_addr;
+        let http = {
+            let db = db.clone();
+            tokio::spawn(async move {
+                match metrics_addr {
+                    Some(addr) => serve_metrics_forever(addr, metrics, db).await,
+                    None => futures_util::future::pending().await,
+                }
+            })
+        };
+
+        let signal = tokio::select! {
+            r = mon => return r?,
+            r = &mut udp => return r?,
+            r = &mut tcp => return r?,
+            r = http => return r?,
+            r = snapshots => return r?,
+            r = retention => return r?,
+            signal = shutdown_signal() => signal?,
+        };
+
+        tracing::info!("Received {}, shutting down", signal);
+        let deadline = tokio::time::Instant::now() + SHUTDOWN_TIMEOUT;
+        // stop taking new queries and datagrams. queued entries are still written
+        tcp.abort();
+        let _ = shutdown_tx.send(true);
+        match tokio::time::timeout_at(deadline, udp).await {
+            Ok(r) => r??,
+            Err(_) => tracing::warn!("Timed out writing queued history"),
+        }
+        // queries that arrive on open connections from here on wait behind `idle`
+        let idle = tokio::time::timeout_at(deadline, in_flight.wait_idle()).await;
+        if idle.is_err() {
+            tracing::warn!("Timed out waiting for queries to finish");
+        }
+        let checkpoint = db
+            .write(|con| {
+                con.execute_batch("PRAGMA wal_checkpoint(TRUNCATE);")
+                    .map_err(anyhow::Error::from)
+            })
+            .await;
+        in_flight.close();
+        drop(idle);
+        checkpoint?;
+        tracing::info!("Shut down cleanly");
         Ok(())
     })
 }
+
+/// Wait for SIGINT, SIGTERM or SIGHUP, and name which one arrived
+async fn shutdown_signal() -> Result<&'static str> {
+    let mut int = signal(SignalKind::interrupt())?;
+    let mut term = signal(SignalKind::terminate())?;
+    let mut hup = signal(SignalKind::hangup())?;
+    Ok(tokio::select! {
+        _ = int.recv() => "SIGINT",
+        _ = term.recv() => "SIGTERM",
+        _ = hup.recv() => "SIGHUP",
+    })
+}
@@ -0,0 +1,240 @@
+use anyhow::Result;
+use chrono::prelude::*;
+use clap::Parser;
+use stybulate::{Cell, Headers, Style, Table};
+use tarpc::context;
+
+use super::query::remove_zero_width_graphemes;
+use crate::tcp::{HistoryQueryServiceClient, StatsQuery};
+
+const BAR_WIDTH: usize = 40;
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+
+/// Summarize shell history: top commands, busiest places, failure rates and activity over time
+#[derive(Parser, Debug)]
+pub struct StatsOptions {
+    /// Show N rows in each of the top lists
+    #[clap(value_name = "N", short = 'n', long = "--limit", default_value = "10")]
+    limit: u32,
+
+    /// Only count entries since the specified date.
+    #[clap(value_name = "TIME", short = 's', long)]
+    since: Option<String>,
+
+    /// Only count entries before date.
+    #[clap(value_name = "TIME", short = 'u', long)]
+    until: Option<String>,
+
+    /// Only count entries from host HOSTNAME (default: all hosts).
+    #[clap(value_name = "HOSTNAME", long)]
+    host: Option<String>,
+}
+
+pub async fn stats_main(client: HistoryQueryServiceClient, options: StatsOptions) -> Result<()> {
+    let parse_time = |x: Option<&String>| -> Result<Option<i64>> {
+        match x {
+            Some(s) => Ok(Some(crate::util::parse_time(s)?)),
+            None => Ok(None),
+        }
+    };
+    let query = StatsQuery {
+        host: options.host,
+        since: parse_time(options.since.as_ref())?,
+        until: parse_time(options.until.as_ref())?,
+        limit: options.limit,
+    };
+    tracing::debug!("{:#?}", query);
+    let stats = client.stats(context::current(), query).await??;
+
+    println!("{} commands", stats.total);
+    if stats.total == 0 {
+        return Ok(());
+    }
+
+    let top = |title: &str, rows: &[(String, i64)]| {
+        table(
+            &[title, "count"],
+            rows.iter()
+                .map(|(key, n)| {
+                    vec![
+                        Cell::from(&remove_zero_width_graphemes(key)),
+                        Cell::Int(*n as i32),
+                    ]
+                })
+                .collect(),
+        )
+    };
+    top("command", &stats.top_commands);
+    top("program", &stats.top_programs);
+    top("dir", &stats.top_dirs);
+    top("host", &stats.top_hosts);
+
+    if !stats.failure_rates.is_empty() {
+        table(
+            &["failing command", "runs", "failed", "rate"],
+            stats
+                .failure_rates
+                .iter()
+                .map(|x| {
+                    vec![
+                        Cell::from(&remove_zero_width_graphemes(&x.argv)),
+                        Cell::Int(x.runs as i32),
+                        Cell::Int(x.failures as i32),
+                        Cell::from(&format!(
+                            "{:.0}%",
+                            100.0 * x.failures as f64 / x.runs as f64
+                        )),
+                    ]
+                })
+                .collect(),
+        );
+    }
+
+    let (by_hour, by_weekday, daily) = local_activity(&Local, &stats.by_quarter_hour);
+    println!("\nby hour");
+    let labels: Vec<String> = (0..24).map(|h| format!("{:02}", h)).collect();
+    chart(
+        labels
+            .iter()
+            .map(|x| x.as_str())
+            .zip(by_hour.iter().copied()),
+    );
+
+    println!("\nby weekday");
+    chart(WEEKDAYS.iter().copied().zip(by_weekday.iter().copied()));
+
+    println!("\nby day");
+    let daily = fill_missing_days(&daily);
+    chart(daily.iter().map(|(day, n)| (day.as_str(), *n)));
+
+    Ok(())
+}
+
+/// Count activity by hour of the day, by weekday (Sunday first) and by day (only days with any
+/// activity) in `tz`, each quarter hour at the offset `tz` had then
+fn local_activity<Tz: TimeZone>(
+    tz: &Tz,
+    by_quarter_hour: &[(i64, i64)],
+) -> (Vec<i64>, Vec<i64>, Vec<(String, i64)>) {
+    let mut by_hour = vec![0; 24];
+    let mut by_weekday = vec![0; 7];
+    let mut daily = std::collections::BTreeMap::new();
+    for &(start, n) in by_quarter_hour {
+        let time = match tz.timestamp_opt(start, 0).single() {
+            Some(time) => time,
+            None => continue,
+        };
+        by_hour[time.hour() as usize] += n;
+        by_weekday[time.weekday().num_days_from_sunday() as usize] += n;
+        *daily.entry(time.date_naive()).or_insert(0) += n;
+    }
+    let daily = daily
+        .into_iter()
+        .map(|(day, n)| (day.format("%Y-%m-%d").to_string(), n))
+        .collect();
+    (by_hour, by_weekday, daily)
+}
+
+fn table(headers: &[&str], rows: Vec<Vec<Cell>>) {
+    println!(
+        "\n{}",
+        Table::new(Style::Plain, rows, Some(Headers::from(headers.to_vec()))).tabulate()
+    );
+}
+
+/// Print a horizontal bar chart, one row per label
+fn chart<'a>(rows: impl Iterator<Item = (&'a str, i64)> + Clone) {
+    let max = rows.clone().map(|(_, n)| n).max().unwrap_or(0);
+    for (label, n) in rows {
+        println!(
+            "{}  {:<width$} {}",
+            label,
+            bar(n, max),
+            n,
+            width = BAR_WIDTH
+        );
+    }
+}
+
+/// Render `value` as a bar of unicode block characters, scaled so that `max` fills BAR_WIDTH
+fn bar(value: i64, max: i64) -> String {
+    const EIGHTHS: [char; 8] = [' ', '▏', '▎', '▍', '▌', '▋', '▊', '▉'];
+    if max <= 0 || value <= 0 {
+        return String::new();
+    }
+    let eighths = (value as f64 / max as f64 * (BAR_WIDTH * 8) as f64).round() as usize;
+    let mut s = "█".repeat(eighths / 8);
+    let partial = EIGHTHS[eighths % 8];
+    if partial != ' ' {
+        s.push(partial);
+    }
+    s
+}
+
+/// Only days with activity are counted. Insert the quiet days in between with a count
+/// of zero so the chart reads as a timeline.
+fn fill_missing_days(daily: &[(String, i64)]) -> Vec<(String, i64)> {
+    let parsed: Vec<(NaiveDate, i64)> = daily
+        .iter()
+        .filter_map(|(day, n)| Some((NaiveDate::parse_from_str(day, "%Y-%m-%d").ok()?, *n)))
+        .collect();
+    let mut result = Vec::new();
+    for (i, (day, n)) in parsed.iter().enumerate() {
+        if let Some((prev, _)) = i.checked_sub(1).map(|j| parsed[j]) {
+            let mut missing = prev.succ_opt();
+            while let Some(m) = missing.filter(|m| m < day) {
+                result.push((m.format("%Y-%m-%d").to_string(), 0));
+                missing = m.succ_opt();
+            }
+        }
+        result.push((day.format("%Y-%m-%d").to_string(), *n));
+    }
+    result
+}
+
+#[test]
+fn test_bar_and_fill_missing_days() {
+    assert_eq!(bar(0, 10), "");
+    assert_eq!(bar(10, 10), "█".repeat(BAR_WIDTH));
+    assert_eq!(bar(5, 10), "█".repeat(BAR_WIDTH / 2));
+    assert_eq!(bar(1, 320), "▏");
+
+    let filled = fill_missing_days(&[("2022-12-30".to_string(), 3), ("2023-01-02".to_string(), 1)]);
+    assert_eq!(
+        filled,
+        vec![
+            ("2022-12-30".to_string(), 3),
+            ("2022-12-31".to_string(), 0),
+            ("2023-01-01".to_string(), 0),
+            ("2023-01-02".to_string(), 1),
+        ]
+    );
+}
+
+#[test]
+fn test_local_activity() {
+    // 2023-01-01 is a Sunday
+    let midnight_utc = NaiveDate::from_ymd_opt(2023, 1, 1)
+        .unwrap()
+        .and_hms_opt(0, 0, 0)
+        .unwrap()
+        .timestamp();
+    let quarters = [
+        (midnight_utc - 900, 1),
+        (midnight_utc, 2),
+        (midnight_utc + 1800, 4),
+    ];
+
+    let (by_hour, by_weekday, daily) = local_activity(&Utc, &quarters);
+    assert_eq!((by_hour[23], by_hour[0]), (1, 6));
+    assert_eq!((by_weekday[6], by_weekday[0]), (1, 6));
+    assert_eq!(
+        daily,
+        [("2022-12-31".to_string(), 1), ("2023-01-01".to_string(), 6)]
+    );
+
+    // half an hour off UTC splits what was one hour in two
+    let (by_hour, _, _) =
+        local_activity(&FixedOffset::east_opt(5 * 3600 + 1800).unwrap(), &quarters);
+    assert_eq!((by_hour[5], by_hour[6]), (3, 4));
+}
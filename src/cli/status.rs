@@ -0,0 +1,115 @@
+use anyhow::Result;
+use chrono::prelude::*;
+use stybulate::{Cell, Headers, Style, Table};
+use tarpc::context;
+
+use crate::tcp::HistoryQueryServiceClient;
+
+/// Print what the server is up to: version, uptime, database size, ingestion counters and
+/// recently active hosts
+pub async fn status_main(client: HistoryQueryServiceClient) -> Result<()> {
+    let status = client.status(context::current()).await??;
+
+    println!(
+        "server:    {} pid={} version={} up {}",
+        status.hostname,
+        status.pid,
+        status.version,
+        format_duration(status.uptime_secs)
+    );
+    if status.version != crate::VERSION {
+        println!("           (this client is version {})", crate::VERSION);
+    }
+    println!(
+        "database:  {} ({})",
+        status.db_path,
+        format_bytes(status.db_size_bytes)
+    );
+    println!(
+        "rows:      {}",
+        status
+            .row_counts
+            .iter()
+            .map(|(table, n)| format!("{}={}", table, n))
+            .collect::<Vec<_>>()
+            .join(" ")
+    );
+    let ingest = &status.ingest;
+    println!(
+        "ingest:    received={} parsed={} parse_failures={} inserted={} insert_failures={} duplicates={}",
+        ingest.received,
+        ingest.parsed,
+        ingest.parse_failures,
+        ingest.inserted,
+        ingest.insert_failures,
+        ingest.duplicates
+    );
+
+    if let Some(replica) = &status.replica {
+        let synced = match replica.last_contact {
+            Some(time) => format!(
+                "through id {}, last contact {}",
+                replica.last_id,
+                Local
+                    .timestamp_opt(time, 0)
+                    .single()
+                    .unwrap_or_default()
+                    .format("%m/%d %-I:%M:%S%p")
+            ),
+            None => "never reached".to_string(),
+        };
+        println!("replica:   of {} ({})", replica.primary, synced);
+        if let Some(error) = &replica.error {
+            println!("           {}", error);
+        }
+    }
+
+    if status.recent_hosts.is_empty() {
+        println!("\nNo hosts have sent history in the last day");
+        return Ok(());
+    }
+    let rows = status
+        .recent_hosts
+        .iter()
+        .map(|(host, time)| {
+            let last = Local.timestamp_opt(*time, 0).single().unwrap_or_default();
+            vec![
+                Cell::from(host),
+                Cell::from(&last.format("%m/%d %-I:%M%p").to_string()),
+            ]
+        })
+        .collect();
+    println!(
+        "\n{}",
+        Table::new(
+            Style::Plain,
+            rows,
+            Some(Headers::from(vec!["host", "last seen"]))
+        )
+        .tabulate()
+    );
+    Ok(())
+}
+
+fn format_duration(secs: u64) -> String {
+    match secs {
+        s if s < 60 => format!("{}s", s),
+        s if s < 60 * 60 => format!("{}m {}s", s / 60, s % 60),
+        s if s < 24 * 60 * 60 => format!("{}h {}m", s / 3600, s % 3600 / 60),
+        s => format!("{}d {}h", s / 86400, s % 86400 / 3600),
+    }
+}
+
+fn format_bytes(n: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = n as f64;
+    let mut unit = 0;
+    while size >= 1000.0 && unit + 1 < UNITS.len() {
+        size /= 1000.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} B", n),
+        _ => format!("{:.1} {}", size, UNITS[unit]),
+    }
+}
@@ -0,0 +1,67 @@
+use anyhow::Result;
+use clap::Parser;
+use tarpc::context;
+
+use crate::tcp::HistoryQueryServiceClient;
+
+/// Tag an entry, to find it again with `history --tag TAG`. Tagged entries are never removed
+/// by the retention policy. Entry ids are shown by `history --ids`.
+#[derive(Parser, Debug)]
+pub struct TagOptions {
+    #[clap(value_name = "ID")]
+    id: i64,
+
+    #[clap(value_name = "TAG", required = true, value_parser = parse_tag)]
+    tags: Vec<String>,
+}
+
+/// Remove tags from an entry
+#[derive(Parser, Debug)]
+pub struct UntagOptions {
+    #[clap(value_name = "ID")]
+    id: i64,
+
+    #[clap(value_name = "TAG", required = true)]
+    tags: Vec<String>,
+}
+
+/// Attach a note to an entry, like "this is how to rotate the cert", replacing any it has
+#[derive(Parser, Debug)]
+pub struct NoteOptions {
+    #[clap(value_name = "ID")]
+    id: i64,
+
+    #[clap(value_name = "TEXT", required_unless_present = "remove")]
+    note: Option<String>,
+
+    /// Remove the entry's note instead
+    #[clap(long, conflicts_with = "note")]
+    remove: bool,
+}
+
+fn parse_tag(s: &str) -> Result<String> {
+    crate::tags::validate_tag(s)?;
+    Ok(s.to_string())
+}
+
+pub async fn tag_main(client: HistoryQueryServiceClient, options: TagOptions) -> Result<()> {
+    client
+        .add_tags(context::current(), options.id, options.tags)
+        .await??;
+    Ok(())
+}
+
+pub async fn untag_main(client: HistoryQueryServiceClient, options: UntagOptions) -> Result<()> {
+    client
+        .remove_tags(context::current(), options.id, options.tags)
+        .await??;
+    Ok(())
+}
+
+pub async fn note_main(client: HistoryQueryServiceClient, options: NoteOptions) -> Result<()> {
+    let note = options.note.filter(|_| !options.remove);
+    client
+        .set_note(context::current(), options.id, note)
+        .await??;
+    Ok(())
+}
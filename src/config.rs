@@ -0,0 +1,75 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::path::PathBuf;
+
+/// Settings from `~/.config/history/config.toml` (or `$XDG_CONFIG_HOME/history/config.toml`,
+/// or wherever `HISTORY_CONFIG` points). Everything is optional, and a missing file is the same
+/// as an empty one.
+///
+/// ```toml
+/// [federation]
+/// # other history servers to search along with this shell's own
+/// servers = ["login.cluster-a", "login.cluster-b"]
+/// # also search them from ctrl-r
+/// isearch = true
+///
+/// [ingest]
+/// # send history over TCP, with acknowledgements, rather than UDP
+/// reliable = true
+///
+/// [context]
+/// # what to record along with each command. all of these by default
+/// fields = ["repo", "branch", "venv", "conda", "user", "euid", "ssh", "tmux", "shlvl"]
+/// ```
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub federation: Federation,
+    pub ingest: Ingest,
+    pub context: ContextFields,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Federation {
+    pub servers: Vec<String>,
+    pub isearch: bool,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Ingest {
+    pub reliable: bool,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ContextFields {
+    /// Which context the shell sends with each command. All of it when unset.
+    pub fields: Option<Vec<String>>,
+}
+
+fn config_path() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os("HISTORY_CONFIG") {
+        return Some(PathBuf::from(path));
+    }
+    let dir = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(dir.join("history").join("config.toml"))
+}
+
+impl Config {
+    pub fn load() -> Result<Config> {
+        let path = match config_path() {
+            Some(path) => path,
+            None => return Ok(Config::default()),
+        };
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Config::default()),
+            Err(e) => return Err(e).with_context(|| format!("Unable to read {}", path.display())),
+        };
+        toml::from_str(&text).with_context(|| format!("Unable to parse {}", path.display()))
+    }
+}
@@ -0,0 +1,126 @@
+use anyhow::{Context, Result};
+use rusqlite::{Connection, OpenFlags};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Semaphore;
+
+use crate::schema::create_schema;
+
+/// How long a connection waits on a lock held by another connection before giving up
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Shared handle to the history database.
+///
+/// All writes go through a single connection, so they're serialized the same way sqlite would
+/// serialize them anyway. Reads use a pool of read-only connections, which in WAL mode don't
+/// block on the writer or on each other, so a slow query doesn't hold up ingestion or anyone
+/// else's ctrl-r. Both run on tokio's blocking thread pool.
+#[derive(Clone)]
+pub struct Database {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    path: PathBuf,
+    writer: Mutex<Connection>,
+    /// Idle read-only connections. Opened lazily, up to `max_readers`.
+    readers: Mutex<Vec<Connection>>,
+    reader_permits: Semaphore,
+}
+
+impl Database {
+    pub fn open(path: impl AsRef<Path>) -> Result<Database> {
+        let path = path.as_ref().to_path_buf();
+        let writer = Connection::open(&path)
+            .with_context(|| format!("Unable to open database {}", path.display()))?;
+        writer.busy_timeout(BUSY_TIMEOUT)?;
+        create_schema(&writer)?;
+
+        let max_readers = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(4)
+            .clamp(2, 8);
+        Ok(Database {
+            inner: Arc::new(Inner {
+                path,
+                writer: Mutex::new(writer),
+                readers: Mutex::new(Vec::new()),
+                reader_permits: Semaphore::new(max_readers),
+            }),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.inner.path
+    }
+
+    /// Size on disk, including the write-ahead log
+    pub fn size_bytes(&self) -> u64 {
+        let mut wal = self.inner.path.clone().into_os_string();
+        wal.push("-wal");
+        [self.inner.path.as_os_str(), wal.as_os_str()]
+            .iter()
+            .filter_map(|p| std::fs::metadata(p).ok())
+            .map(|m| m.len())
+            .sum()
+    }
+
+    /// Run `f` with a read-only connection
+    pub async fn read<T, E, F>(&self, f: F) -> Result<T, E>
+    where
+        F: FnOnce(&Connection) -> Result<T, E> + Send + 'static,
+        T: Send + 'static,
+        E: From<anyhow::Error> + Send + 'static,
+    {
+        let _permit = self
+            .inner
+            .reader_permits
+            .acquire()
+            .await
+            .map_err(anyhow::Error::from)?;
+        let inner = self.inner.clone();
+        tokio::task::spawn_blocking(move || {
+            let pooled = inner.readers.lock().unwrap().pop();
+            let con = match pooled {
+                Some(con) => con,
+                None => open_reader(&inner.path)?,
+            };
+            let result = f(&con);
+            inner.readers.lock().unwrap().push(con);
+            result
+        })
+        .await
+        .map_err(anyhow::Error::from)?
+    }
+
+    /// Run `f` with the (only) read-write connection
+    pub async fn write<T, E, F>(&self, f: F) -> Result<T, E>
+    where
+        F: FnOnce(&mut Connection) -> Result<T, E> + Send + 'static,
+        T: Send + 'static,
+        E: From<anyhow::Error> + Send + 'static,
+    {
+        let inner = self.inner.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut con = inner.writer.lock().unwrap();
+            f(&mut con)
+        })
+        .await
+        .map_err(anyhow::Error::from)?
+    }
+}
+
+fn open_reader(path: &Path) -> Result<Connection> {
+    let con = Connection::open_with_flags(
+        path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY
+            | OpenFlags::SQLITE_OPEN_NO_MUTEX
+            | OpenFlags::SQLITE_OPEN_URI,
+    )
+    .with_context(|| format!("Unable to open database {} for reading", path.display()))?;
+    con.busy_timeout(BUSY_TIMEOUT)?;
+    // this is per-connection, so it isn't inherited from the schema setup
+    con.execute_batch("PRAGMA case_sensitive_like = true;")?;
+    Ok(con)
+}
@@ -1,16 +1,32 @@
 use std::env::VarError;
 
 mod _vendor_ctty;
+mod backup;
 pub mod cli;
+mod config;
+mod db;
+mod listen;
+mod local;
+mod merge;
+mod metrics;
 mod monitor;
+mod pidfile;
+mod places;
+mod replication;
+mod repo;
+mod retention;
 mod schema;
+mod tags;
 mod tcp;
 mod udp;
 mod util;
 
 pub const HISTORY_PORT: u16 = 29080;
+const VERSION: &str = git_version::git_version!(fallback = "0.1");
 lazy_static::lazy_static! {
     static ref HISTORY_SERVER: Result<String, VarError> = std::env::var("__history_server");
+    static ref HISTORY_REPLICA: Result<String, VarError> = std::env::var("__history_replica");
+    static ref SESSION: Result<String, VarError> = std::env::var("__history_session");
     static ref MYHOSTNAME: String = util::getshorthostname();
     static ref CWD: String = std::env::var("__history_pwd").unwrap_or_else(|_| std::env::current_dir().unwrap().display().to_string());
     pub static ref HISTORY_MODE: Result<String, VarError> = std::env::var("__history_mode");
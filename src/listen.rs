@@ -0,0 +1,144 @@
+use anyhow::{anyhow, Context, Result};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Where the server takes history and queries from
+#[derive(Debug, Clone)]
+pub enum Listen {
+    /// The Unix sockets under `$XDG_RUNTIME_DIR`, from this user only
+    Local,
+    /// Port 29080 on `bind`, from peers in `allowlist`
+    Network {
+        bind: IpAddr,
+        allowlist: Arc<Allowlist>,
+    },
+}
+
+/// Rejected peers are logged at most this often, with a count of the ones in between
+const REJECT_LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A network like `10.0.0.0/8` or `fd00::/8`. A bare address is a network of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Cidr> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .with_context(|| format!("Invalid address in {}", s))?;
+        let max_len = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix_len = match prefix_len {
+            Some(len) => len
+                .parse()
+                .ok()
+                .filter(|len| *len <= max_len)
+                .ok_or_else(|| anyhow!("Invalid prefix length in {}", s))?,
+            None => max_len,
+        };
+        Ok(Cidr { addr, prefix_len })
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // a dual-stack socket reports IPv4 peers as ::ffff:a.b.c.d
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(net: &[u8], ip: &[u8], prefix_len: u8) -> bool {
+    let (bytes, bits) = ((prefix_len / 8) as usize, prefix_len % 8);
+    if net[..bytes] != ip[..bytes] {
+        return false;
+    }
+    bits == 0 || (net[bytes] ^ ip[bytes]) >> (8 - bits) == 0
+}
+
+/// The peers the server will talk to. An empty allowlist allows everyone.
+#[derive(Debug, Default)]
+pub struct Allowlist {
+    networks: Vec<Cidr>,
+    /// When a rejection was last logged, and how many there have been since
+    rejections: Mutex<(Option<Instant>, u64)>,
+}
+
+impl Allowlist {
+    pub fn new(networks: Vec<Cidr>) -> Allowlist {
+        Allowlist {
+            networks,
+            ..Default::default()
+        }
+    }
+
+    /// Whether `peer` is allowed. Rejections are logged, but not more than once a minute, so
+    /// that a misconfigured shell (or someone scanning the port) can't flood the log.
+    pub fn allows(&self, peer: IpAddr, what: &str) -> bool {
+        if self.networks.is_empty() || self.networks.iter().any(|net| net.contains(peer)) {
+            return true;
+        }
+        let mut rejections = self.rejections.lock().unwrap();
+        let (last_logged, unlogged) = &mut *rejections;
+        match last_logged {
+            Some(t) if t.elapsed() < REJECT_LOG_INTERVAL => *unlogged += 1,
+            _ => {
+                match *unlogged {
+                    0 => warn!("Rejected {} from {}, which isn't in the allowlist", what, peer),
+                    n => warn!(
+                        "Rejected {} from {}, which isn't in the allowlist, and {} more since the last report",
+                        what, peer, n
+                    ),
+                }
+                *last_logged = Some(Instant::now());
+                *unlogged = 0;
+            }
+        }
+        false
+    }
+}
+
+#[test]
+fn test_cidr() {
+    let net: Cidr = "10.9.0.0/16".parse().unwrap();
+    assert!(net.contains("10.9.200.1".parse().unwrap()));
+    assert!(!net.contains("10.10.0.1".parse().unwrap()));
+    assert!(net.contains("::ffff:10.9.0.1".parse().unwrap()));
+    let net: Cidr = "192.168.1.128/25".parse().unwrap();
+    assert!(net.contains("192.168.1.200".parse().unwrap()));
+    assert!(!net.contains("192.168.1.127".parse().unwrap()));
+    let host: Cidr = "fd00::1".parse().unwrap();
+    assert!(host.contains("fd00::1".parse().unwrap()));
+    assert!(!host.contains("fd00::2".parse().unwrap()));
+    assert!("0.0.0.0/0"
+        .parse::<Cidr>()
+        .unwrap()
+        .contains("8.8.8.8".parse().unwrap()));
+    assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+    assert!("nonsense/8".parse::<Cidr>().is_err());
+}
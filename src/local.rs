@@ -0,0 +1,51 @@
+use anyhow::{Context, Result};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+/// The server address that means "this machine, over Unix sockets", as in
+/// `history --eval local`. Nothing is exposed to the network, and each user gets their own
+/// sockets, so several users can run a server on the same machine.
+pub const LOCAL_SERVER: &str = "local";
+
+const INGEST_SOCKET: &str = "ingest.sock";
+const QUERY_SOCKET: &str = "query.sock";
+
+/// `$XDG_RUNTIME_DIR/history`, which holds the sockets
+fn socket_dir() -> Result<PathBuf> {
+    let runtime_dir = std::env::var_os("XDG_RUNTIME_DIR")
+        .context("XDG_RUNTIME_DIR isn't set, so there's nowhere to put the local sockets")?;
+    Ok(PathBuf::from(runtime_dir).join("history"))
+}
+
+pub fn ingest_socket_path() -> Result<PathBuf> {
+    Ok(socket_dir()?.join(INGEST_SOCKET))
+}
+
+pub fn query_socket_path() -> Result<PathBuf> {
+    Ok(socket_dir()?.join(QUERY_SOCKET))
+}
+
+/// Get `path` ready for a socket to be bound there: its directory exists and is only
+/// accessible by us, and any socket left by a previous server is gone. The pidfile lock
+/// means no other server is still using it.
+pub fn prepare_socket_path(path: &Path) -> Result<()> {
+    let dir = path.parent().context("Socket path has no directory")?;
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+        .with_context(|| format!("Unable to create {}", dir.display()))?;
+    // in case it was created by someone with a looser umask
+    std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700))?;
+    match std::fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e).with_context(|| format!("Unable to remove {}", path.display())),
+    }
+}
+
+/// Limit a freshly bound socket to its owner
+pub fn restrict_socket(path: &Path) -> Result<()> {
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+        .with_context(|| format!("Unable to set permissions on {}", path.display()))
+}
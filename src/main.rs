@@ -1,6 +1,6 @@
 use anyhow::Result;
 use history::cli::register_tracing;
-use history::cli::{isearch_main, query_client_main, server_main};
+use history::cli::{isearch_main, query_client_main, send_main, server_main};
 
 fn main() -> Result<()> {
     let rt = || {
@@ -12,6 +12,7 @@ fn main() -> Result<()> {
 
     match history::HISTORY_MODE.as_ref().map(|x| x as &str) {
         Ok("server") => server_main(), // tracing is registered later
+        Ok("send") => send_main(),
         Ok("isearch") => Ok(rt().block_on(async { isearch_main().await })?),
         _ => {
             register_tracing(false)?;
@@ -0,0 +1,183 @@
+use anyhow::{Context, Result};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// What `merge` did
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct MergeSummary {
+    /// History rows in the other database
+    pub examined: u64,
+    pub inserted: u64,
+    /// Rows that were already here: same session, host, dir, command and time
+    pub duplicates: u64,
+    pub new_commands: u64,
+    pub new_places: u64,
+}
+
+/// Copy the history in the database at `other` into `con`. The other database's `commands`
+/// and `places` ids mean nothing here, so rows are matched up by their contents and given the
+/// ids they have (or now get) in this database. All or nothing.
+pub fn merge(con: &mut Connection, other: &Path) -> Result<MergeSummary> {
+    // ATTACH would happily create an empty database
+    if !other.is_file() {
+        anyhow::bail!("{} doesn't exist", other.display());
+    }
+    con.execute(
+        "ATTACH DATABASE ? AS other",
+        [other.to_string_lossy().as_ref()],
+    )
+    .with_context(|| format!("Unable to open {}", other.display()))?;
+    let result = merge_attached(con);
+    con.execute_batch("DETACH DATABASE other")?;
+    result
+}
+
+fn merge_attached(con: &mut Connection) -> Result<MergeSummary> {
+    // databases from before session ids (or history numbers) don't have the column
+    let other_column = |name: &str, expr: &'static str| -> Result<&'static str> {
+        let present: bool = con.query_row(
+            "SELECT count(*) FROM pragma_table_info('history', 'other') WHERE name = ?",
+            [name],
+            |row| row.get(0),
+        )?;
+        Ok(if present { expr } else { "NULL" })
+    };
+    let other_session_id = other_column("session_id", "h.session_id")?;
+    let other_history_number = other_column("history_number", "h.history_number")?;
+    // tables hanging off history rows, and their columns besides history_id, in databases new
+    // enough to have them
+    let mut annotations = Vec::new();
+    for (table, columns) in [
+        ("context", "key, value"),
+        ("tags", "tag"),
+        ("notes", "note"),
+    ] {
+        let present: bool = con.query_row(
+            "SELECT count(*) FROM other.sqlite_master WHERE type = 'table' AND name = ?",
+            [table],
+            |row| row.get(0),
+        )?;
+        if present {
+            annotations.push((table, columns));
+        }
+    }
+
+    let tx = con.transaction()?;
+    let mut summary = MergeSummary {
+        examined: tx.query_row("SELECT count(*) FROM other.history", [], |row| row.get(0))?,
+        ..Default::default()
+    };
+    summary.new_commands = tx.execute(
+        "INSERT OR IGNORE INTO main.commands (argv) SELECT argv FROM other.commands",
+        [],
+    )? as u64;
+    summary.new_places = tx.execute(
+        "INSERT OR IGNORE INTO main.places (host, dir) SELECT host, dir FROM other.places",
+        [],
+    )? as u64;
+    summary.inserted = tx.execute(
+        &format!(
+            "
+            INSERT INTO main.history (session, session_id, command_id, place_id, exit_status, end_time, history_number)
+            SELECT h.session, {other_session_id}, c.id, p.id, h.exit_status, h.end_time, {other_history_number}
+            FROM other.history h
+            JOIN other.commands oc ON h.command_id = oc.id
+            JOIN other.places op ON h.place_id = op.id
+            JOIN main.commands c ON c.argv IS oc.argv
+            JOIN main.places p ON p.host IS op.host AND p.dir IS op.dir
+            WHERE NOT EXISTS (
+                SELECT 1 FROM main.history m
+                WHERE m.command_id = c.id
+                AND m.place_id = p.id
+                AND m.end_time IS h.end_time
+                AND m.session IS h.session
+                AND m.session_id IS {other_session_id}
+            )
+            ORDER BY h.end_time, h.id
+            "
+        ),
+        [],
+    )? as u64;
+    for (table, columns) in annotations {
+        // every row matched up with where it came from the same way duplicates are recognized,
+        // so that a tag on an entry both databases have isn't lost. what's already here wins.
+        let x_columns = columns
+            .split(", ")
+            .map(|c| format!("x.{c}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        tx.execute(
+            &format!(
+                "
+                INSERT OR IGNORE INTO main.{table} (history_id, {columns})
+                SELECT m.id, {x_columns}
+                FROM other.{table} x
+                JOIN other.history h ON x.history_id = h.id
+                JOIN other.commands oc ON h.command_id = oc.id
+                JOIN other.places op ON h.place_id = op.id
+                JOIN main.commands c ON c.argv IS oc.argv
+                JOIN main.places p ON p.host IS op.host AND p.dir IS op.dir
+                JOIN main.history m ON m.command_id = c.id
+                    AND m.place_id = p.id
+                    AND m.end_time IS h.end_time
+                    AND m.session IS h.session
+                    AND m.session_id IS {other_session_id}
+                "
+            ),
+            [],
+        )?;
+    }
+    tx.commit()?;
+    summary.duplicates = summary.examined.saturating_sub(summary.inserted);
+    Ok(summary)
+}
+
+#[test]
+fn test_merge() {
+    use crate::udp::{insert, test_entry, RpcMessage};
+
+    let entry = |argv: &str, dir: &str, time: u64| RpcMessage {
+        context: vec![("branch".to_string(), dir.to_string())],
+        ..test_entry(dir, argv, time)
+    };
+    let path = std::env::temp_dir().join(format!("history-test-merge-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let other = Connection::open(&path).unwrap();
+    crate::schema::create_schema(&other).unwrap();
+    for msg in [
+        entry("ls", "/a", 1),
+        entry("make", "/b", 2),
+        entry("ls", "/b", 3),
+    ] {
+        insert(&other, &msg, None).unwrap();
+    }
+    other
+        .execute("INSERT INTO tags (history_id, tag) VALUES (2, 'build')", [])
+        .unwrap();
+    drop(other);
+
+    let mut con = Connection::open_in_memory().unwrap();
+    crate::schema::create_schema(&con).unwrap();
+    insert(&con, &entry("make", "/b", 2), None).unwrap();
+    insert(&con, &entry("vim", "/a", 4), None).unwrap();
+
+    let summary = merge(&mut con, &path).unwrap();
+    assert_eq!(
+        (summary.examined, summary.inserted, summary.duplicates),
+        (3, 2, 1)
+    );
+    assert_eq!((summary.new_commands, summary.new_places), (1, 0));
+    let contexts: i64 = con
+        .query_row("SELECT count(*) FROM context", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(contexts, 4);
+    // including the tag on an entry that was already here
+    let tags: i64 = con
+        .query_row("SELECT count(*) FROM tags", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(tags, 1);
+    let again = merge(&mut con, &path).unwrap();
+    assert_eq!(again.inserted, 0);
+    std::fs::remove_file(&path).unwrap();
+}
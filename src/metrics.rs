@@ -0,0 +1,346 @@
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use sysinfo::{ProcessExt, System, SystemExt};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, error, info};
+
+use crate::db::Database;
+use crate::udp::IngestStats;
+
+/// Upper bounds of the latency histogram buckets, in seconds
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+
+/// Cumulative latency histogram, in the shape Prometheus expects
+#[derive(Debug, Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        for (bucket, bound) in self.buckets.iter().zip(LATENCY_BUCKETS) {
+            if secs <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let sep = if labels.is_empty() { "" } else { "," };
+        for (bucket, bound) in self.buckets.iter().zip(LATENCY_BUCKETS) {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}{}le=\"{}\"}} {}",
+                name,
+                labels,
+                sep,
+                bound,
+                bucket.load(Ordering::Relaxed)
+            );
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let braces = |labels: &str| match labels.is_empty() {
+            true => String::new(),
+            false => format!("{{{}}}", labels),
+        };
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{}{}le=\"+Inf\"}} {}",
+            name, labels, sep, count
+        );
+        let _ = writeln!(out, "{}_count{} {}", name, braces(labels), count);
+        let _ = writeln!(
+            out,
+            "{}_sum{} {}",
+            name,
+            braces(labels),
+            self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6
+        );
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RpcStats {
+    pub calls: AtomicU64,
+    pub errors: AtomicU64,
+    pub latency: Histogram,
+}
+
+/// Everything the server counts, for the status RPC and the metrics endpoint
+#[derive(Debug)]
+pub struct Metrics {
+    pub ingest: Arc<IngestStats>,
+    rpcs: Mutex<BTreeMap<&'static str, Arc<RpcStats>>>,
+}
+
+impl Metrics {
+    pub fn new(ingest: Arc<IngestStats>) -> Metrics {
+        Metrics {
+            ingest,
+            rpcs: Default::default(),
+        }
+    }
+
+    pub fn observe_rpc(&self, name: &'static str, elapsed: Duration, ok: bool) {
+        let stats = self.rpcs.lock().unwrap().entry(name).or_default().clone();
+        stats.calls.fetch_add(1, Ordering::Relaxed);
+        if !ok {
+            stats.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        stats.latency.observe(elapsed);
+    }
+
+    /// Render in the OpenMetrics text format
+    fn render(&self, db: &Database, system: &mut System) -> String {
+        let mut out = String::new();
+        let family = |out: &mut String, name: &str, kind: &str, help: &str| {
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+        };
+        let ingest = &self.ingest;
+        for (name, help, value) in [
+            (
+                "history_datagrams_received",
+                "UDP datagrams received",
+                &ingest.received,
+            ),
+            (
+                "history_entries_inserted",
+                "History entries written to the database",
+                &ingest.inserted,
+            ),
+            (
+                "history_insert_failures",
+                "History entries that couldn't be written to the database",
+                &ingest.insert_failures,
+            ),
+            (
+                "history_duplicates_dropped",
+                "History entries dropped because the shell sent the same one again",
+                &ingest.duplicates,
+            ),
+            (
+                "history_insert_batches",
+                "Write transactions committed by the ingestion writer",
+                &ingest.batches,
+            ),
+        ] {
+            family(&mut out, name, "counter", help);
+            let _ = writeln!(out, "{}_total {}", name, value.load(Ordering::Relaxed));
+        }
+
+        family(
+            &mut out,
+            "history_datagram_parse_errors",
+            "counter",
+            "UDP datagrams that couldn't be parsed, by reason",
+        );
+        for (kind, n) in ingest.parse_errors.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "history_datagram_parse_errors_total{{kind=\"{}\"}} {}",
+                kind, n
+            );
+        }
+
+        family(
+            &mut out,
+            "history_last_insert_timestamp_seconds",
+            "gauge",
+            "Unix time of the most recent successful insert",
+        );
+        let _ = writeln!(
+            out,
+            "history_last_insert_timestamp_seconds {}",
+            ingest.last_insert.load(Ordering::Relaxed)
+        );
+
+        family(
+            &mut out,
+            "history_insert_batch_duration_seconds",
+            "histogram",
+            "Time to write one batch of history entries",
+        );
+        ingest
+            .insert_latency
+            .render(&mut out, "history_insert_batch_duration_seconds", "");
+
+        let rpcs = self.rpcs.lock().unwrap().clone();
+        family(
+            &mut out,
+            "history_rpc_requests",
+            "counter",
+            "Query RPCs served",
+        );
+        for (rpc, stats) in rpcs.iter() {
+            let _ = writeln!(
+                out,
+                "history_rpc_requests_total{{rpc=\"{}\"}} {}",
+                rpc,
+                stats.calls.load(Ordering::Relaxed)
+            );
+        }
+        family(
+            &mut out,
+            "history_rpc_errors",
+            "counter",
+            "Query RPCs that returned an error",
+        );
+        for (rpc, stats) in rpcs.iter() {
+            let _ = writeln!(
+                out,
+                "history_rpc_errors_total{{rpc=\"{}\"}} {}",
+                rpc,
+                stats.errors.load(Ordering::Relaxed)
+            );
+        }
+        family(
+            &mut out,
+            "history_rpc_duration_seconds",
+            "histogram",
+            "Time to serve a query RPC",
+        );
+        for (rpc, stats) in rpcs.iter() {
+            stats.latency.render(
+                &mut out,
+                "history_rpc_duration_seconds",
+                &format!("rpc=\"{}\"", rpc),
+            );
+        }
+
+        family(
+            &mut out,
+            "history_db_size_bytes",
+            "gauge",
+            "Size of the database, including the write-ahead log",
+        );
+        let _ = writeln!(out, "history_db_size_bytes {}", db.size_bytes());
+
+        if let Ok(pid) = sysinfo::get_current_pid() {
+            system.refresh_process(pid);
+            if let Some(proc) = system.process(pid) {
+                let disk = proc.disk_usage();
+                family(
+                    &mut out,
+                    "process_cpu_usage_percent",
+                    "gauge",
+                    "CPU usage since the previous scrape",
+                );
+                let _ = writeln!(out, "process_cpu_usage_percent {}", proc.cpu_usage());
+                family(
+                    &mut out,
+                    "process_resident_memory_bytes",
+                    "gauge",
+                    "Resident memory size",
+                );
+                let _ = writeln!(
+                    out,
+                    "process_resident_memory_bytes {}",
+                    proc.memory() * 1024
+                );
+                family(
+                    &mut out,
+                    "process_disk_read_bytes",
+                    "counter",
+                    "Bytes read from disk",
+                );
+                let _ = writeln!(
+                    out,
+                    "process_disk_read_bytes_total {}",
+                    disk.total_read_bytes
+                );
+                family(
+                    &mut out,
+                    "process_disk_written_bytes",
+                    "counter",
+                    "Bytes written to disk",
+                );
+                let _ = writeln!(
+                    out,
+                    "process_disk_written_bytes_total {}",
+                    disk.total_written_bytes
+                );
+                family(
+                    &mut out,
+                    "process_start_time_seconds",
+                    "gauge",
+                    "Unix time the process started",
+                );
+                let _ = writeln!(out, "process_start_time_seconds {}", proc.start_time());
+            }
+        }
+
+        out.push_str("# EOF\n");
+        out
+    }
+}
+
+/// Serve the metrics over HTTP at `addr`, for Prometheus to scrape. This only speaks enough
+/// HTTP/1.1 for that: every request gets the metrics (or a 404), then the connection closes.
+pub async fn serve_metrics_forever(
+    addr: SocketAddr,
+    metrics: Arc<Metrics>,
+    db: Database,
+) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("Serving metrics on http://{}/metrics", addr);
+    let system = Arc::new(tokio::sync::Mutex::new(System::new()));
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let (metrics, db, system) = (metrics.clone(), db.clone(), system.clone());
+        tokio::spawn(async move {
+            if let Err(e) = handle_scrape(stream, &metrics, &db, &system).await {
+                error!("Serving metrics to {}: {:#}", peer, e);
+            }
+        });
+    }
+}
+
+async fn handle_scrape(
+    mut stream: TcpStream,
+    metrics: &Metrics,
+    db: &Database,
+    system: &tokio::sync::Mutex<System>,
+) -> Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await??;
+        if n == 0 || request.len() > 16 * 1024 {
+            return Ok(());
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    let request_line = String::from_utf8_lossy(&request);
+    let path = request_line.split_whitespace().nth(1).unwrap_or("");
+    debug!("Metrics request for {}", path);
+
+    let response = match path {
+        "/metrics" | "/" => {
+            let body = metrics.render(db, &mut *system.lock().await);
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/openmetrics-text; version=1.0.0; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
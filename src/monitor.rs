@@ -1,9 +1,12 @@
 use anyhow::Result;
+use std::sync::Arc;
 use std::time::Duration;
 use sysinfo::{ProcessExt, System, SystemExt};
 use tracing::info;
 
-pub async fn server_monitor_log_forever() -> Result<()> {
+use crate::udp::IngestStats;
+
+pub async fn server_monitor_log_forever(ingest: Arc<IngestStats>) -> Result<()> {
     let mut system = System::new();
     let pid = sysinfo::get_current_pid().expect("failed to get current pid");
 
@@ -32,5 +35,6 @@ pub async fn server_monitor_log_forever() -> Result<()> {
             disk.written_bytes / 1_000,
             proc.memory(),
         );
+        info!("[Ingest]: {}", ingest);
     }
 }
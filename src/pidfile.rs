@@ -0,0 +1,111 @@
+use anyhow::{Context, Result};
+use nix::fcntl::{flock, FlockArg};
+use nix::sys::signal::kill;
+use nix::unistd::Pid;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// How long to keep trying for the lock while whoever holds it hasn't written the pid of a live
+/// process. That's a server that's still starting, or just `server_state` taking a look.
+const ACQUIRE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The lock that makes a server the only one serving a database. It's an flock on
+/// `<db>.pid`, which also holds the server's pid. The kernel drops the lock when the
+/// process dies, however it dies, so a pidfile nobody has locked is stale.
+pub struct PidFile {
+    file: File,
+    /// Pid of a previous server that died without cleaning up
+    stale: Option<i32>,
+}
+
+/// What the pidfile for a database says about its server
+#[derive(Debug, PartialEq, Eq)]
+pub enum ServerState {
+    Running(Option<i32>),
+    /// The server with this pid died without cleaning up
+    Stale(i32),
+    Stopped,
+}
+
+pub fn pidfile_path(db: &Path) -> PathBuf {
+    let mut path = db.as_os_str().to_owned();
+    path.push(".pid");
+    PathBuf::from(path)
+}
+
+impl PidFile {
+    /// Take the lock, or None if another server holds it. Doesn't write our pid yet, since
+    /// daemonizing changes it.
+    pub fn acquire(path: &Path) -> Result<Option<PidFile>> {
+        let start = Instant::now();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            // the pid in it tells us whether the last server died without cleaning up
+            .truncate(false)
+            .open(path)
+            .with_context(|| format!("Unable to open {}", path.display()))?;
+        while flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock).is_err() {
+            let holder = read_pid(&mut file)?;
+            if holder.is_some_and(is_alive) || start.elapsed() >= ACQUIRE_TIMEOUT {
+                return Ok(None);
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        let stale = read_pid(&mut file)?;
+        // until our pid is written, the lock alone says a server is starting
+        file.set_len(0)?;
+        Ok(Some(PidFile { file, stale }))
+    }
+
+    pub fn write_pid(&mut self) -> Result<()> {
+        if let Some(pid) = self.stale.take() {
+            tracing::info!("Replacing stale pidfile left by pid {}", pid);
+        }
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        writeln!(self.file, "{}", std::process::id())?;
+        self.file.sync_all()?;
+        Ok(())
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        // emptied rather than removed: someone else may already have it open, waiting to lock
+        // it, and they should end up locking the same file as everyone after them
+        let _ = self.file.set_len(0);
+    }
+}
+
+pub fn server_state(db: &Path) -> Result<ServerState> {
+    let path = pidfile_path(db);
+    let mut file = match File::open(&path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(ServerState::Stopped),
+        Err(e) => return Err(e).with_context(|| format!("Unable to open {}", path.display())),
+    };
+    let pid = read_pid(&mut file)?;
+    if flock(file.as_raw_fd(), FlockArg::LockSharedNonblock).is_err() {
+        return Ok(ServerState::Running(pid));
+    }
+    Ok(match pid {
+        Some(pid) => ServerState::Stale(pid),
+        None => ServerState::Stopped,
+    })
+}
+
+fn is_alive(pid: i32) -> bool {
+    kill(Pid::from_raw(pid), None).is_ok()
+}
+
+fn read_pid(file: &mut File) -> Result<Option<i32>> {
+    let mut text = String::new();
+    file.seek(SeekFrom::Start(0))?;
+    file.read_to_string(&mut text)?;
+    Ok(text.trim().parse().ok())
+}
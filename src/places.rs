@@ -0,0 +1,217 @@
+use anyhow::Result;
+use rusqlite::{named_params, Connection};
+use serde::{Deserialize, Serialize};
+
+/// What `move_dir` did, or would do
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct MoveDirSummary {
+    /// Places whose dir was rewritten
+    pub places: u64,
+    /// Of those, the ones that already existed under the new dir, and were merged into it
+    pub merged: u64,
+    /// History entries in the places that moved
+    pub entries: u64,
+}
+
+/// Point the history recorded in `old` and the dirs under it at `new` instead, on `host` or on
+/// every host. Where a rewritten dir is already in `places`, its entries are moved over to that
+/// row, since (host, dir) is unique. All or nothing, and rolled back if `dry_run`.
+pub fn move_dir(
+    con: &mut Connection,
+    old: &str,
+    new: &str,
+    host: Option<&str>,
+    dry_run: bool,
+) -> Result<MoveDirSummary> {
+    for dir in [old, new] {
+        if !dir.starts_with('/') || dir == "/" || dir.ends_with('/') {
+            anyhow::bail!("Expected an absolute path other than /, not {:?}", dir);
+        }
+    }
+    if old == new {
+        anyhow::bail!("{} and {} are the same", old, new);
+    }
+    if new.starts_with(&format!("{}/", old)) {
+        anyhow::bail!("Can't move {} inside itself", old);
+    }
+
+    let tx = con.transaction()?;
+    tx.execute_batch(
+        "
+        DROP TABLE IF EXISTS temp.moves;
+        CREATE TEMP TABLE moves (place_id int primary key, new_dir text, target_id int);
+        ",
+    )?;
+    let params = named_params! { ":old": old, ":new": new, ":host": host };
+    tx.execute(
+        "
+        INSERT INTO moves (place_id, new_dir)
+        SELECT id, :new || substr(dir, length(:old) + 1)
+        FROM places
+        WHERE (dir = :old OR substr(dir, 1, length(:old) + 1) = :old || '/')
+          AND (:host IS NULL OR host = :host)
+        ",
+        params,
+    )?;
+    // the places already at the new dirs that are staying put. When `old` is under `new`, some
+    // new dirs are held by places that are moving out of the way themselves.
+    tx.execute(
+        "
+        UPDATE moves SET target_id = (
+            SELECT p.id FROM places p, places o
+            WHERE o.id = moves.place_id AND p.host IS o.host AND p.dir = moves.new_dir
+              AND p.id NOT IN (SELECT place_id FROM moves)
+        )
+        ",
+        [],
+    )?;
+    let mut summary = MoveDirSummary::default();
+    (summary.places, summary.merged, summary.entries) = tx.query_row(
+        "
+        SELECT count(*), count(target_id),
+            (SELECT count(*) FROM history WHERE place_id IN (SELECT place_id FROM moves))
+        FROM moves
+        ",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    )?;
+
+    // the checkout roots recorded with the entries moved along with their dirs
+    tx.execute(
+        "
+        UPDATE context SET value = :new || substr(value, length(:old) + 1)
+        WHERE key = 'repo'
+          AND (value = :old OR substr(value, 1, length(:old) + 1) = :old || '/')
+          AND history_id IN (
+              SELECT id FROM history WHERE place_id IN (SELECT place_id FROM moves)
+          )
+        ",
+        named_params! { ":old": old, ":new": new },
+    )?;
+    tx.execute_batch(
+        "
+        UPDATE history SET place_id = (
+            SELECT target_id FROM moves WHERE moves.place_id = history.place_id
+        )
+        WHERE place_id IN (SELECT place_id FROM moves WHERE target_id IS NOT NULL);
+        DELETE FROM places WHERE id IN (SELECT place_id FROM moves WHERE target_id IS NOT NULL);
+        -- out of each other's way first, so that no rename hits unique(host, dir) part way through
+        UPDATE places SET dir = 'moving:' || id
+        WHERE id IN (SELECT place_id FROM moves WHERE target_id IS NULL);
+        UPDATE places SET dir = (SELECT new_dir FROM moves WHERE moves.place_id = places.id)
+        WHERE id IN (SELECT place_id FROM moves WHERE target_id IS NULL);
+        DROP TABLE temp.moves;
+        ",
+    )?;
+    match dry_run {
+        true => tx.rollback()?,
+        false => tx.commit()?,
+    }
+    Ok(summary)
+}
+
+#[test]
+fn test_move_dir() {
+    use crate::udp::{insert, test_entry, RpcMessage};
+
+    let mut con = Connection::open_in_memory().unwrap();
+    crate::schema::create_schema(&con).unwrap();
+    let entry = |host: &str, dir: &str, argv: &str| RpcMessage {
+        host: host.to_string(),
+        context: vec![("repo".to_string(), "/src/x".to_string())],
+        ..test_entry(dir, argv, 1)
+    };
+    for msg in [
+        entry("a", "/src/x", "make"),
+        entry("a", "/src/x/lib", "cargo test"),
+        entry("a", "/src/xy", "ls"),
+        entry("a", "/work/x/lib", "vim"),
+        entry("b", "/src/x", "make"),
+    ] {
+        insert(&con, &msg, None).unwrap();
+    }
+    let dirs = |con: &Connection| -> Vec<(String, String, i64)> {
+        con.prepare(
+            "SELECT host, dir, count(history.id) FROM places
+            LEFT JOIN history ON history.place_id = places.id
+            GROUP BY places.id ORDER BY host, dir",
+        )
+        .unwrap()
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+        .unwrap()
+        .collect::<rusqlite::Result<_>>()
+        .unwrap()
+    };
+    let before = dirs(&con);
+
+    let dry = move_dir(&mut con, "/src/x", "/work/x", Some("a"), true).unwrap();
+    assert_eq!((dry.places, dry.merged, dry.entries), (2, 1, 2));
+    assert_eq!(dirs(&con), before);
+
+    let summary = move_dir(&mut con, "/src/x", "/work/x", Some("a"), false).unwrap();
+    assert_eq!((summary.places, summary.merged), (2, 1));
+    let expected = [
+        ("a", "/src/xy", 1),
+        ("a", "/work/x", 1),
+        ("a", "/work/x/lib", 2),
+        ("b", "/src/x", 1),
+    ]
+    .map(|(h, d, n)| (h.to_string(), d.to_string(), n));
+    assert_eq!(dirs(&con), expected);
+    let moved_roots: i64 = con
+        .query_row(
+            "SELECT count(*) FROM context WHERE key = 'repo' AND value = '/work/x'",
+            [],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(moved_roots, 2);
+
+    assert!(move_dir(&mut con, "/work", "/work/x", None, false).is_err());
+}
+
+#[test]
+fn test_move_dir_into_parent() {
+    use crate::udp::{insert, test_entry};
+
+    let mut con = Connection::open_in_memory().unwrap();
+    crate::schema::create_schema(&con).unwrap();
+    for dir in ["/w/x", "/w/x/sub", "/w/x/sub/sub", "/v/sub", "/v/sub/sub"] {
+        insert(&con, &test_entry(dir, &format!("ls {}", dir), 1), None).unwrap();
+    }
+
+    // /w/x is already there to merge into; /v isn't, so /v/sub has to be renamed out of the way
+    let summary = move_dir(&mut con, "/w/x/sub", "/w/x", None, false).unwrap();
+    assert_eq!((summary.places, summary.merged), (2, 1));
+    move_dir(&mut con, "/v/sub", "/v", None, false).unwrap();
+
+    let mut stmt = con
+        .prepare(
+            "SELECT dir, group_concat(argv, ',') FROM history
+            JOIN places ON places.id = history.place_id
+            JOIN commands ON commands.id = history.command_id
+            GROUP BY dir ORDER BY dir",
+        )
+        .unwrap();
+    let dirs: Vec<(String, String)> = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        .unwrap()
+        .collect::<rusqlite::Result<_>>()
+        .unwrap();
+    let expected = [
+        ("/v", "ls /v/sub"),
+        ("/v/sub", "ls /v/sub/sub"),
+        ("/w/x", "ls /w/x,ls /w/x/sub"),
+        ("/w/x/sub", "ls /w/x/sub/sub"),
+    ]
+    .map(|(d, a)| (d.to_string(), a.to_string()));
+    assert_eq!(dirs, expected);
+    let dangling: i64 = con
+        .query_row(
+            "SELECT count(*) FROM history WHERE place_id NOT IN (SELECT id FROM places)",
+            [],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(dangling, 0);
+}
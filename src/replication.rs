@@ -0,0 +1,195 @@
+use anyhow::{Context, Result};
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use tarpc::{client, context, tokio_serde::formats::Bincode};
+use tokio::sync::Notify;
+use tracing::{info, warn};
+
+use crate::db::Database;
+use crate::tcp::HistoryQueryServiceClient;
+use crate::udp::{insert, RpcMessage};
+
+/// Most rows to ask the primary for at once
+pub const REPLICATION_BATCH_SIZE: u32 = 1000;
+/// How long to wait before asking again once caught up
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// How long to wait before reconnecting after losing the primary
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Where a replica is at, as reported by the status RPC
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReplicaStatus {
+    pub primary: String,
+    /// Highest history id on the primary copied so far
+    pub last_id: i64,
+    /// Unix time of the last successful exchange with the primary
+    pub last_contact: Option<i64>,
+    /// Why the last attempt to reach the primary failed, if it did
+    pub error: Option<String>,
+}
+
+/// A server following a primary. It copies new history rows, along with their commands and
+/// places, until it's promoted, at which point it starts accepting history itself.
+///
+/// Only new rows are replicated: edits and deletions on the primary after a row was copied
+/// aren't, and neither are tags and notes.
+pub struct Replica {
+    status: Mutex<ReplicaStatus>,
+    promoted: AtomicBool,
+    promote: Notify,
+}
+
+impl Replica {
+    pub fn new(primary: String) -> Replica {
+        Replica {
+            status: Mutex::new(ReplicaStatus {
+                primary,
+                last_id: 0,
+                last_contact: None,
+                error: None,
+            }),
+            promoted: AtomicBool::new(false),
+            promote: Notify::new(),
+        }
+    }
+
+    /// None once promoted
+    pub fn status(&self) -> Option<ReplicaStatus> {
+        match self.promoted.load(Ordering::Relaxed) {
+            true => None,
+            false => Some(self.status.lock().unwrap().clone()),
+        }
+    }
+
+    /// Stop following the primary. Returns false if this was already promoted.
+    pub fn promote(&self) -> bool {
+        let already = self.promoted.swap(true, Ordering::Relaxed);
+        if !already {
+            self.promote.notify_one();
+        }
+        !already
+    }
+
+    /// Pick up from where the last run left off. A database that has history but was never
+    /// replicated into is refused, since the primary's ids could clash with its own.
+    pub async fn resume(&self, db: &Database) -> Result<()> {
+        let primary = self.status.lock().unwrap().primary.clone();
+        let last_id = db
+            .read(move |con| -> Result<i64> {
+                let last_id = con
+                    .query_row(
+                        "SELECT last_id FROM replication WHERE primary_addr = ?",
+                        [&primary],
+                        |row| row.get(0),
+                    )
+                    .optional()?;
+                let has_history: bool =
+                    con.query_row("SELECT EXISTS (SELECT 1 FROM history)", [], |row| {
+                        row.get(0)
+                    })?;
+                match (last_id, has_history) {
+                    (Some(last_id), _) => Ok(last_id),
+                    (None, false) => Ok(0),
+                    (None, true) => anyhow::bail!(
+                        "The database already has history that wasn't replicated from {}. \
+                         Start the replica with an empty database.",
+                        primary
+                    ),
+                }
+            })
+            .await?;
+        self.status.lock().unwrap().last_id = last_id;
+        Ok(())
+    }
+
+    pub async fn run_until_promoted(&self, db: &Database) {
+        let primary = self.status.lock().unwrap().primary.clone();
+        info!("Replicating from {}", primary);
+        tokio::select! {
+            _ = self.promote.notified() => {}
+            _ = self.follow(db, &primary) => {}
+        }
+        info!(
+            "Promoted to primary. No longer replicating from {}",
+            primary
+        );
+    }
+
+    async fn follow(&self, db: &Database, primary: &str) {
+        loop {
+            if let Err(e) = self.sync(db, primary).await {
+                let msg = format!("{:#}", e);
+                let mut status = self.status.lock().unwrap();
+                // log once per outage rather than on every retry
+                if status.error.as_ref() != Some(&msg) {
+                    warn!("Replicating from {}: {}", primary, msg);
+                }
+                status.error = Some(msg);
+            }
+            tokio::time::sleep(RETRY_INTERVAL).await;
+        }
+    }
+
+    /// Copy rows from the primary until the connection fails
+    async fn sync(&self, db: &Database, primary: &str) -> Result<()> {
+        let transport = tarpc::serde_transport::tcp::connect(
+            format!("{}:{}", primary, crate::HISTORY_PORT),
+            Bincode::default,
+        )
+        .await
+        .context("Connecting to primary")?;
+        let client = HistoryQueryServiceClient::new(client::Config::default(), transport).spawn();
+
+        let mut last_id = self.status.lock().unwrap().last_id;
+        loop {
+            let batch = client
+                .replicate(context::current(), last_id, REPLICATION_BATCH_SIZE)
+                .await??;
+            let n = batch.len();
+            if let Some((id, _)) = batch.last() {
+                last_id = *id;
+                let primary = primary.to_string();
+                db.write(move |con| apply_batch(con, &primary, &batch))
+                    .await?;
+            }
+
+            let now = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)?
+                .as_secs() as i64;
+            {
+                let mut status = self.status.lock().unwrap();
+                if status.error.take().is_some() {
+                    info!("Replicating from {} again", primary);
+                }
+                status.last_id = last_id;
+                status.last_contact = Some(now);
+            }
+
+            if n < REPLICATION_BATCH_SIZE as usize {
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+        }
+    }
+}
+
+fn apply_batch(
+    con: &mut rusqlite::Connection,
+    primary: &str,
+    batch: &[(i64, RpcMessage)],
+) -> Result<()> {
+    let tx = con.transaction()?;
+    for (id, msg) in batch {
+        insert(&tx, msg, Some(*id))?;
+    }
+    if let Some((last_id, _)) = batch.last() {
+        tx.execute(
+            "INSERT OR REPLACE INTO replication (primary_addr, last_id) VALUES (?, ?)",
+            params![primary, last_id],
+        )?;
+    }
+    tx.commit()?;
+    Ok(())
+}
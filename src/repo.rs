@@ -0,0 +1,132 @@
+use std::path::{Path, PathBuf};
+
+/// The git checkout a directory is in
+#[derive(Debug, PartialEq, Eq)]
+pub struct Repo {
+    /// The top of the working tree
+    pub root: PathBuf,
+    /// The URL of the `origin` remote, or of the first remote if there's no `origin`
+    pub remote: Option<String>,
+}
+
+/// Find the checkout enclosing `dir`, the same way the shell hook's __history_git does
+pub fn find_repo(dir: &Path) -> Option<Repo> {
+    let root = dir.ancestors().find(|d| d.join(".git").exists())?;
+    let mut git = root.join(".git");
+    // worktrees and submodules have a file pointing at the real git dir
+    if git.is_file() {
+        let contents = std::fs::read_to_string(&git).ok()?;
+        git = root.join(contents.trim().strip_prefix("gitdir: ")?);
+    }
+    // and a worktree's config is in the git dir of the checkout it was made from
+    if let Ok(common) = std::fs::read_to_string(git.join("commondir")) {
+        git = git.join(common.trim());
+    }
+    let remote = std::fs::read_to_string(git.join("config"))
+        .ok()
+        .and_then(|config| remote_url(&config));
+    Some(Repo {
+        root: root.to_path_buf(),
+        remote,
+    })
+}
+
+/// The URL of the `origin` remote in a git config file, or else of the first remote
+fn remote_url(config: &str) -> Option<String> {
+    let mut section = "";
+    let mut first = None;
+    for line in config.lines().map(str::trim) {
+        if line.starts_with('[') {
+            section = line;
+            continue;
+        }
+        let url = match line.split_once('=') {
+            Some((key, value)) if key.trim() == "url" => value.trim(),
+            _ => continue,
+        };
+        if section == r#"[remote "origin"]"# {
+            return Some(url.to_string());
+        }
+        if section.starts_with(r#"[remote ""#) && first.is_none() {
+            first = Some(url.to_string());
+        }
+    }
+    first
+}
+
+/// A repository's identity, from the URL of its remote, which is the same however it was
+/// cloned: `git@github.com:me/x.git`, `https://github.com/me/x` and `ssh://git@github.com/me/x`
+/// all come out as `github.com/me/x`
+pub fn repo_identity(url: &str) -> String {
+    let url = url.trim();
+    let (has_scheme, rest) = match url.split_once("://") {
+        Some((_, rest)) => (true, rest),
+        None => (false, url),
+    };
+    let (authority, path) = match (has_scheme, rest.split_once(':')) {
+        // scp-style host:path, as long as the colon comes before any slash
+        (false, Some((host, path))) if !host.contains('/') => (host, path),
+        _ => rest.split_once('/').unwrap_or((rest, "")),
+    };
+    let host = authority.rsplit('@').next().unwrap_or(authority);
+    // a port doesn't change which repository it is
+    let host = match host.rsplit_once(':') {
+        Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) => host,
+        _ => host,
+    };
+    let path = path.trim_matches('/');
+    let path = path.strip_suffix(".git").unwrap_or(path);
+    match host.is_empty() {
+        // a local path, like file:///srv/git/x.git
+        true => format!("/{}", path),
+        false => format!("{}/{}", host.to_lowercase(), path),
+    }
+}
+
+#[test]
+fn test_repo_identity() {
+    for url in [
+        "git@github.com:me/x.git",
+        "https://github.com/me/x",
+        "https://user@GitHub.com/me/x.git/",
+        "ssh://git@github.com:22/me/x.git",
+        "github.com:me/x",
+    ] {
+        assert_eq!(repo_identity(url), "github.com/me/x", "{}", url);
+    }
+    assert_eq!(repo_identity("file:///srv/git/x.git"), "/srv/git/x");
+}
+
+#[test]
+fn test_find_repo() {
+    let dir = std::env::temp_dir().join(format!("history-test-repo-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let git = dir.join("x/.git");
+    std::fs::create_dir_all(git.join("worktrees/wt")).unwrap();
+    std::fs::create_dir_all(dir.join("x/src/deep")).unwrap();
+    std::fs::create_dir_all(dir.join("wt")).unwrap();
+    std::fs::write(
+        git.join("config"),
+        "[core]\n\tbare = false\n[remote \"upstream\"]\n\turl = git@host:up/x.git\n\
+         [remote \"origin\"]\n\turl = git@host:me/x.git\n\tfetch = +refs/heads/*\n",
+    )
+    .unwrap();
+    std::fs::write(git.join("worktrees/wt/commondir"), "../..\n").unwrap();
+    std::fs::write(
+        dir.join("wt/.git"),
+        format!("gitdir: {}\n", git.join("worktrees/wt").display()),
+    )
+    .unwrap();
+
+    let repo = find_repo(&dir.join("x/src/deep")).unwrap();
+    assert_eq!(repo.root, dir.join("x"));
+    assert_eq!(repo.remote.as_deref(), Some("git@host:me/x.git"));
+    let worktree = find_repo(&dir.join("wt")).unwrap();
+    assert_eq!(worktree.root, dir.join("wt"));
+    assert_eq!(worktree.remote, repo.remote);
+    assert_eq!(
+        remote_url("[remote \"upstream\"]\n\turl = a\n[remote \"fork\"]\n\turl = b\n").as_deref(),
+        Some("a")
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
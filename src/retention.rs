@@ -0,0 +1,319 @@
+use anyhow::Result;
+use rusqlite::{Connection, Params};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info};
+
+use crate::db::Database;
+use crate::replication::Replica;
+
+/// Entries that aren't tagged. Tagged entries are kept regardless of the policy.
+const UNTAGGED: &str = "id NOT IN (SELECT history_id FROM tags)";
+
+/// What to throw away. Any combination can be set; with none set, nothing is removed.
+/// Tagged entries are never removed.
+#[derive(Debug, Clone, Default)]
+pub struct RetentionPolicy {
+    /// Drop entries older than this many days
+    pub max_age_days: Option<u32>,
+    /// Keep only the latest N runs of each command in each place
+    pub max_runs: Option<u32>,
+    /// Drop the oldest entries until the database fits in this many bytes
+    pub max_size_bytes: Option<u64>,
+    /// VACUUM afterwards, to give the freed space back to the filesystem. This rewrites the
+    /// whole file, and ingestion waits while it does.
+    pub vacuum: bool,
+}
+
+impl RetentionPolicy {
+    pub fn is_empty(&self) -> bool {
+        self.max_age_days.is_none() && self.max_runs.is_none() && self.max_size_bytes.is_none()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RetentionReport {
+    pub expired: usize,
+    pub excess_runs: usize,
+    pub over_size: usize,
+    pub commands: usize,
+    pub places: usize,
+}
+
+impl std::fmt::Display for RetentionReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "expired={} excess_runs={} over_size={} unused_commands={} unused_places={}",
+            self.expired, self.excess_runs, self.over_size, self.commands, self.places
+        )
+    }
+}
+
+/// Apply `policy` now, then every `interval`. Nothing is removed while `replica` is following
+/// its primary, since tags aren't replicated and it would throw away entries tagged there.
+pub async fn retention_forever(
+    db: Database,
+    policy: RetentionPolicy,
+    replica: Option<Arc<Replica>>,
+    interval: Duration,
+) -> Result<()> {
+    info!(
+        "Applying retention policy {:?} every {:?}",
+        policy, interval
+    );
+    loop {
+        if let Some(status) = replica.as_ref().and_then(|r| r.status()) {
+            info!(
+                "[Retention]: not applied while replicating from {}",
+                status.primary
+            );
+            tokio::time::sleep(interval).await;
+            continue;
+        }
+        let p = policy.clone();
+        match db.write(move |con| apply(con, &p)).await {
+            Ok(report) => info!("[Retention]: {}", report),
+            Err(e) => error!("Applying retention policy: {:#}", e),
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+pub fn apply(con: &mut Connection, policy: &RetentionPolicy) -> Result<RetentionReport> {
+    let mut report = RetentionReport::default();
+    let tx = con.transaction()?;
+    if let Some(days) = policy.max_age_days {
+        report.expired = delete_history(
+            &tx,
+            &format!(
+                "SELECT id FROM history
+                WHERE end_time < CAST(strftime('%s', 'now') AS INTEGER) - ? * 24 * 60 * 60
+                AND {UNTAGGED}"
+            ),
+            [days],
+        )?;
+    }
+    if let Some(n) = policy.max_runs {
+        // tagged runs are kept on top of the latest N, rather than counting towards them
+        report.excess_runs = delete_history(
+            &tx,
+            &format!(
+                "SELECT id FROM (
+                    SELECT id, row_number() OVER (
+                        PARTITION BY command_id, place_id ORDER BY end_time DESC, id DESC
+                    ) AS n
+                    FROM history
+                    WHERE {UNTAGGED}
+                )
+                WHERE n > ?"
+            ),
+            [n],
+        )?;
+    }
+    collect_garbage(&tx, &mut report)?;
+    tx.commit()?;
+
+    if let Some(max) = policy.max_size_bytes {
+        // deleted rows leave free pages behind rather than shrinking the file, so measure the
+        // pages in use. a few rounds, since rows aren't all the same size
+        for _ in 0..5 {
+            let used = used_bytes(con)?;
+            if used <= max {
+                break;
+            }
+            let rows: i64 = con.query_row("SELECT count(*) FROM history", [], |row| row.get(0))?;
+            if rows == 0 {
+                break;
+            }
+            let bytes_per_row = (used / rows as u64).max(1);
+            let excess_rows = ((used - max) / bytes_per_row).max(1);
+            let tx = con.transaction()?;
+            let deleted = delete_history(
+                &tx,
+                &format!("SELECT id FROM history WHERE {UNTAGGED} ORDER BY end_time, id LIMIT ?"),
+                [excess_rows],
+            )?;
+            report.over_size += deleted;
+            collect_garbage(&tx, &mut report)?;
+            tx.commit()?;
+            if deleted == 0 {
+                // everything left is tagged
+                break;
+            }
+        }
+    }
+
+    con.execute_batch("PRAGMA wal_checkpoint(TRUNCATE);")?;
+    if policy.vacuum {
+        con.execute_batch("VACUUM;")?;
+    }
+    Ok(report)
+}
+
+/// Delete the history rows that the query `ids` selects, after the context and notes that refer
+/// to them. The query is run once, since one that depends on the time could pick out different
+/// rows each time.
+fn delete_history(con: &Connection, ids: &str, params: impl Params) -> Result<usize> {
+    con.execute_batch(
+        "
+        DROP TABLE IF EXISTS temp.deleting;
+        CREATE TEMP TABLE deleting (id int primary key);
+        ",
+    )?;
+    con.execute(&format!("INSERT INTO deleting {ids}"), params)?;
+    for table in ["context", "notes"] {
+        con.execute(
+            &format!("DELETE FROM {table} WHERE history_id IN (SELECT id FROM deleting)"),
+            [],
+        )?;
+    }
+    let deleted = con.execute(
+        "DELETE FROM history WHERE id IN (SELECT id FROM deleting)",
+        [],
+    )?;
+    con.execute_batch("DROP TABLE temp.deleting;")?;
+    Ok(deleted)
+}
+
+/// Delete commands and places that no history refers to anymore, and any context or notes left
+/// behind by history deleted some other way
+fn collect_garbage(con: &Connection, report: &mut RetentionReport) -> Result<()> {
+    report.commands += con.execute(
+        "DELETE FROM commands
+        WHERE id NOT IN (SELECT command_id FROM history WHERE command_id IS NOT NULL)",
+        [],
+    )?;
+    report.places += con.execute(
+        "DELETE FROM places
+        WHERE id NOT IN (SELECT place_id FROM history WHERE place_id IS NOT NULL)",
+        [],
+    )?;
+    for table in ["context", "notes"] {
+        con.execute(
+            &format!("DELETE FROM {table} WHERE history_id NOT IN (SELECT id FROM history)"),
+            [],
+        )?;
+    }
+    Ok(())
+}
+
+fn used_bytes(con: &Connection) -> Result<u64> {
+    let (pages, free, page_size): (u64, u64, u64) = con.query_row(
+        "SELECT page_count, freelist_count, page_size
+        FROM pragma_page_count(), pragma_freelist_count(), pragma_page_size()",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    )?;
+    Ok((pages - free) * page_size)
+}
+
+#[test]
+fn test_tagged_entries_are_kept() {
+    use crate::udp::{insert, test_entry};
+
+    let mut con = Connection::open_in_memory().unwrap();
+    crate::schema::create_schema(&con).unwrap();
+    for time in 1..=4 {
+        insert(&con, &test_entry("/", "make", time), None).unwrap();
+    }
+    crate::tags::add_tags(&mut con, 1, &["keep".to_string()]).unwrap();
+    crate::tags::set_note(&con, 2, Some("gone with its entry")).unwrap();
+
+    let policy = RetentionPolicy {
+        max_runs: Some(1),
+        ..Default::default()
+    };
+    let report = apply(&mut con, &policy).unwrap();
+    assert_eq!(report.excess_runs, 2);
+    let ids: Vec<i64> = con
+        .prepare("SELECT id FROM history ORDER BY id")
+        .unwrap()
+        .query_map([], |row| row.get(0))
+        .unwrap()
+        .collect::<rusqlite::Result<_>>()
+        .unwrap();
+    assert_eq!(ids, vec![1, 4]);
+    let notes: i64 = con
+        .query_row("SELECT count(*) FROM notes", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(notes, 0);
+
+    let policy = RetentionPolicy {
+        max_age_days: Some(1),
+        ..Default::default()
+    };
+    assert_eq!(apply(&mut con, &policy).unwrap().expired, 1);
+}
+
+#[test]
+fn test_retention_policies() {
+    use crate::udp::{insert, test_entry as entry};
+
+    let now = chrono::Utc::now().timestamp() as u64;
+    let day = 24 * 60 * 60;
+    let count = |con: &Connection, table: &str| -> i64 {
+        con.query_row(&format!("SELECT count(*) FROM {table}"), [], |row| {
+            row.get(0)
+        })
+        .unwrap()
+    };
+
+    // max_age_days: the old entry goes, and so does its command, which nothing else ran
+    let mut con = Connection::open_in_memory().unwrap();
+    crate::schema::create_schema(&con).unwrap();
+    insert(&con, &entry("/", "old", now - 3 * day), None).unwrap();
+    insert(&con, &entry("/", "new", now - day / 2), None).unwrap();
+    let policy = RetentionPolicy {
+        max_age_days: Some(1),
+        ..Default::default()
+    };
+    let report = apply(&mut con, &policy).unwrap();
+    assert_eq!((report.expired, report.commands), (1, 1));
+    assert_eq!((count(&con, "history"), count(&con, "commands")), (1, 1));
+
+    // max_runs: counted separately for each command in each place
+    let mut con = Connection::open_in_memory().unwrap();
+    crate::schema::create_schema(&con).unwrap();
+    for (i, dir) in ["/a", "/a", "/a", "/b"].into_iter().enumerate() {
+        insert(&con, &entry(dir, "make", i as u64 + 1), None).unwrap();
+    }
+    let policy = RetentionPolicy {
+        max_runs: Some(2),
+        ..Default::default()
+    };
+    assert_eq!(apply(&mut con, &policy).unwrap().excess_runs, 1);
+    let times: Vec<i64> = con
+        .prepare("SELECT end_time FROM history ORDER BY end_time")
+        .unwrap()
+        .query_map([], |row| row.get(0))
+        .unwrap()
+        .collect::<rusqlite::Result<_>>()
+        .unwrap();
+    assert_eq!(times, vec![2, 3, 4]);
+
+    // max_size_bytes: the oldest entries go until what's left fits
+    let mut con = Connection::open_in_memory().unwrap();
+    crate::schema::create_schema(&con).unwrap();
+    for time in 1..=2000 {
+        let argv = format!("echo {} {}", time, "x".repeat(100));
+        insert(&con, &entry("/", &argv, time), None).unwrap();
+    }
+    let max = used_bytes(&con).unwrap() / 2;
+    let policy = RetentionPolicy {
+        max_size_bytes: Some(max),
+        ..Default::default()
+    };
+    let report = apply(&mut con, &policy).unwrap();
+    assert!(report.over_size > 0);
+    assert!(used_bytes(&con).unwrap() <= max);
+    let (oldest, newest): (i64, i64) = con
+        .query_row(
+            "SELECT min(end_time), max(end_time) FROM history",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .unwrap();
+    assert_eq!(oldest, 2001 - count(&con, "history"));
+    assert_eq!(newest, 2000);
+}
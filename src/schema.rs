@@ -23,9 +23,7 @@ pub fn create_schema(con: &Connection) -> Result<()> {
             exit_status int,
             end_time int);
 
-        PRAGMA user_version = 1;
         PRAGMA journal_mode = WAL;
-        PRAGMA locking_mode = EXCLUSIVE;
         PRAGMA synchronous = normal;
         PRAGMA case_sensitive_like = true;
 
@@ -35,6 +33,76 @@ pub fn create_schema(con: &Connection) -> Result<()> {
         create index if not exists history_command_place on history(command_id, place_id);
 ",
     )?;
+    migrate(con)?;
 
     Ok(())
 }
+
+/// Schema changes after version 1, in order. MIGRATIONS[i] upgrades a database from
+/// user_version i + 1 to i + 2. Only ever append to this list.
+const MIGRATIONS: &[&str] = &[
+    // 2: `session` is now just the /dev/pts number, which is reused as soon as a terminal closes,
+    // so each shell also gets a globally unique id
+    "
+    alter table history add column session_id text;
+    create index if not exists history_session on history(session_id);
+    ",
+    // 3: the shell's number for each entry, so that sending the same entry twice can be noticed
+    "
+    alter table history add column history_number int;
+    ",
+    // 4: optional context sent along with each entry, like the git branch or virtualenv
+    "
+    create table if not exists context (
+        history_id int references history (id),
+        key text,
+        value text,
+        primary key (history_id, key)
+    );
+    create index if not exists context_key_value on context(key, value);
+    ",
+    // 5: tags and a note that people attach to entries they want to find again
+    "
+    create table if not exists tags (
+        history_id int references history (id),
+        tag text,
+        primary key (history_id, tag)
+    );
+    create index if not exists tags_tag on tags(tag);
+    create table if not exists notes (
+        history_id int primary key references history (id),
+        note text
+    );
+    ",
+    // 6: to find the other executions in a query result's group, newest first
+    "
+    create index if not exists history_command_place_time on history(command_id, place_id, end_time);
+    create index if not exists history_command_time on history(command_id, end_time);
+    ",
+    // 7: how far a replica has got, in its primary's history ids
+    "
+    create table if not exists replication (
+        primary_addr text primary key,
+        last_id int not null
+    );
+    ",
+];
+
+fn migrate(con: &Connection) -> Result<()> {
+    let mut version: usize = con.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version == 0 {
+        // freshly created, or created before we kept track of the version
+        version = 1;
+    }
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version - 1) {
+        let to = i + 2;
+        tracing::info!("Migrating database schema to version {}", to);
+        con.execute_batch(&format!(
+            "BEGIN; {} PRAGMA user_version = {}; COMMIT;",
+            migration, to
+        ))?;
+        version = to;
+    }
+    con.execute_batch(&format!("PRAGMA user_version = {}", version))?;
+    Ok(())
+}
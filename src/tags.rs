@@ -0,0 +1,141 @@
+use anyhow::Result;
+use rusqlite::{params, Connection};
+
+/// Tags are shown and passed around joined by commas and spaces, so they can't contain either
+pub fn validate_tag(tag: &str) -> Result<()> {
+    if tag.is_empty() || tag.contains(|c: char| c == ',' || c.is_whitespace()) {
+        anyhow::bail!(
+            "Invalid tag {:?}: tags can't be empty or contain commas or spaces",
+            tag
+        );
+    }
+    Ok(())
+}
+
+fn check_exists(con: &Connection, id: i64) -> Result<()> {
+    let exists: bool = con.query_row("SELECT count(*) FROM history WHERE id = ?", [id], |row| {
+        row.get(0)
+    })?;
+    if !exists {
+        anyhow::bail!("There's no history entry with id {}", id);
+    }
+    Ok(())
+}
+
+/// Tag the entry `id` with each of `tags`, returning how many it didn't already have
+pub fn add_tags(con: &mut Connection, id: i64, tags: &[String]) -> Result<usize> {
+    for tag in tags {
+        validate_tag(tag)?;
+    }
+    let tx = con.transaction()?;
+    check_exists(&tx, id)?;
+    let mut added = 0;
+    for tag in tags {
+        added += tx.execute(
+            "INSERT OR IGNORE INTO tags (history_id, tag) VALUES (?, ?)",
+            params![id, tag],
+        )?;
+    }
+    tx.commit()?;
+    Ok(added)
+}
+
+/// Take `tags` off the entry `id`, returning how many it had
+pub fn remove_tags(con: &mut Connection, id: i64, tags: &[String]) -> Result<usize> {
+    let tx = con.transaction()?;
+    check_exists(&tx, id)?;
+    let mut removed = 0;
+    for tag in tags {
+        removed += tx.execute(
+            "DELETE FROM tags WHERE history_id = ? AND tag = ?",
+            params![id, tag],
+        )?;
+    }
+    tx.commit()?;
+    Ok(removed)
+}
+
+/// Replace the note on the entry `id`, or remove it if `note` is None
+pub fn set_note(con: &Connection, id: i64, note: Option<&str>) -> Result<()> {
+    check_exists(con, id)?;
+    match note {
+        Some(note) => con.execute(
+            "INSERT OR REPLACE INTO notes (history_id, note) VALUES (?, ?)",
+            params![id, note],
+        )?,
+        None => con.execute("DELETE FROM notes WHERE history_id = ?", [id])?,
+    };
+    Ok(())
+}
+
+/// The tags, in order, and the latest note on the entry `id` and on every other entry with the
+/// same values in the history columns `same`, like `["command_id", "place_id"]` for everything
+/// a grouped query row stands for
+pub fn annotations(
+    con: &Connection,
+    id: i64,
+    same: &[&str],
+) -> rusqlite::Result<(Vec<String>, Option<String>)> {
+    let group = match same.is_empty() {
+        true => "o.id = e.id".to_string(),
+        false => same
+            .iter()
+            .map(|c| format!("o.{c} = e.{c}"))
+            .collect::<Vec<_>>()
+            .join(" AND "),
+    };
+    let entries = format!("SELECT o.id FROM history o, history e WHERE e.id = ? AND {group}");
+    let mut stmt = con.prepare_cached(&format!(
+        "SELECT DISTINCT tag FROM tags WHERE history_id IN ({entries}) ORDER BY tag"
+    ))?;
+    let tags = stmt
+        .query_map([id], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    let mut stmt = con.prepare_cached(&format!(
+        "SELECT note FROM notes WHERE history_id IN ({entries}) ORDER BY history_id DESC LIMIT 1"
+    ))?;
+    let note = stmt.query_map([id], |row| row.get(0))?.next().transpose()?;
+    Ok((tags, note))
+}
+
+#[test]
+fn test_tags_and_notes() {
+    use crate::udp::{insert, test_entry};
+
+    let mut con = Connection::open_in_memory().unwrap();
+    crate::schema::create_schema(&con).unwrap();
+    for time in [1, 2] {
+        insert(&con, &test_entry("/", "openssl req -new", time), None).unwrap();
+    }
+    let tags = |x: &[&str]| x.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+
+    assert_eq!(
+        add_tags(&mut con, 1, &tags(&["deploy", "cert"])).unwrap(),
+        2
+    );
+    assert_eq!(add_tags(&mut con, 1, &tags(&["deploy"])).unwrap(), 0);
+    assert!(add_tags(&mut con, 1, &tags(&["two words"])).is_err());
+    assert!(add_tags(&mut con, 3, &tags(&["deploy"])).is_err());
+    set_note(&con, 1, Some("this is how to rotate the cert")).unwrap();
+    let annotated = (
+        tags(&["cert", "deploy"]),
+        Some("this is how to rotate the cert".to_string()),
+    );
+    assert_eq!(annotations(&con, 1, &[]).unwrap(), annotated);
+    // the later run of the same command stands for both when grouped
+    assert_eq!(annotations(&con, 2, &[]).unwrap(), (vec![], None));
+    assert_eq!(
+        annotations(&con, 2, &["command_id", "place_id"]).unwrap(),
+        annotated
+    );
+
+    assert_eq!(
+        remove_tags(&mut con, 1, &tags(&["cert", "nope"])).unwrap(),
+        1
+    );
+    set_note(&con, 1, None).unwrap();
+    assert_eq!(
+        annotations(&con, 1, &[]).unwrap(),
+        (tags(&["deploy"]), None)
+    );
+}
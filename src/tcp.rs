@@ -1,18 +1,35 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use futures_util::StreamExt;
 use rusqlite::types::ToSqlOutput;
 use rusqlite::ToSql;
-use rusqlite::{named_params, params_from_iter};
+use rusqlite::{named_params, params_from_iter, OptionalExtension};
 use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::Instant;
 use tarpc::{
     context,
+    serde_transport::Transport,
     server::{BaseChannel, Channel},
     tokio_serde::formats::Bincode,
 };
 use thiserror::Error;
-use tokio::sync::Mutex;
-use tracing::{debug, error};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{watch, Semaphore, SemaphorePermit};
+use tracing::{debug, error, info, warn};
+
+use crate::backup::{backup, BackupSummary};
+use crate::db::Database;
+use crate::listen::Listen;
+use crate::local::{prepare_socket_path, query_socket_path, restrict_socket};
+use crate::merge::{merge, MergeSummary};
+use crate::metrics::Metrics;
+use crate::places::{move_dir, MoveDirSummary};
+use crate::replication::{Replica, ReplicaStatus, REPLICATION_BATCH_SIZE};
+use crate::tags::{add_tags, annotations, remove_tags, set_note};
+use crate::udp::{insert, IngestStats, RpcMessage};
 
 #[derive(Error, Debug, Serialize, Deserialize)]
 pub enum RpcError {
@@ -53,53 +70,323 @@ impl From<anyhow::Error> for RpcError {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Query {
     pub host: Option<String>,
     pub command: Option<String>,
     pub exact: bool,
     pub indir: Option<String>,
     pub atdir: Option<String>,
-    pub session: Option<i32>,
+    /// Unique session id, as generated by `history --eval`
+    pub session: Option<String>,
+    /// /dev/pts number
+    pub tty: Option<i32>,
     pub status: Option<String>,
     pub since: Option<i64>,
     pub until: Option<i64>,
-    pub desc: bool,
+    /// Maximum number of rows in the response. The server may return fewer, see QueryPage.
     pub limit: i32,
+    /// Resume after the last row of the previous page
+    pub cursor: Option<Cursor>,
+    pub grouping: Grouping,
+    /// Number of entries from the same shell to include before each match (like grep -B)
+    pub before: u32,
+    /// Number of entries from the same shell to include after each match (like grep -A)
+    pub after: u32,
+    /// Only entries with all of these (key, value) pairs of context
+    pub context: Vec<(String, String)>,
+    /// Only entries with all of these tags
+    pub tags: Vec<String>,
 }
 
+/// Position in a query's results, which are always ordered by (time, id), newest first
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct Cursor {
+    pub time: i64,
+    pub id: i64,
+}
+
+/// One page of query results, newest first. When `next` is set there may be more rows, which
+/// can be fetched by repeating the query with `next` as its cursor.
 #[derive(Serialize, Deserialize, Debug)]
+pub struct QueryPage {
+    pub rows: Vec<QueryResultRow>,
+    pub next: Option<Cursor>,
+}
+
+/// Upper bound on the rows in a single response, so that big exports are sent as a series of
+/// pages rather than one giant message
+pub const MAX_PAGE_SIZE: i32 = 5_000;
+
+/// How query results are deduplicated. Each result row is the most recent execution in its
+/// group, along with the number of executions in the group.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Grouping {
+    /// One row per command per place (host and directory)
+    CommandPlace,
+    /// One row per command
+    Command,
+    /// Every individual execution
+    None,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct IsearchQuery {
     pub command: String,
     pub dir: String,
     pub limit: u32,
     pub offset: u32,
+    /// Rank commands that never succeeded like any other, and don't hide exit status 127.
+    pub include_failures: bool,
+    /// Drop executions that exited with 127 (command not found).
+    pub hide_not_found: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct QueryResultRow {
+    pub id: i64,
     pub time: i64,
-    pub session: i32,
+    pub session: Option<String>,
+    pub tty: Option<i32>,
     pub argv: String,
     pub dir: String,
     pub host: String,
+    /// Number of executions this row stands for, per Query::grouping
+    pub count: i64,
+    /// Neighboring entries from the same session, oldest first, when Query::before is set
+    pub before: Vec<QueryResultRow>,
+    /// Neighboring entries from the same session, oldest first, when Query::after is set
+    pub after: Vec<QueryResultRow>,
+    /// Tags on the entry, or on any entry in its group when the results are grouped
+    pub tags: Vec<String>,
+    /// Likewise, the latest note
+    pub note: Option<String>,
+    /// Root of the git checkout the entry ran in, if the hook recorded it
+    pub repo: Option<String>,
+}
+
+impl QueryResultRow {
+    /// Columns: history.id, end_time, history.session, history.session_id, argv, dir, host, count
+    fn from_sql(row: &rusqlite::Row) -> rusqlite::Result<QueryResultRow> {
+        Ok(QueryResultRow {
+            id: row.get(0)?,
+            time: row.get(1)?,
+            tty: row.get(2)?,
+            session: row.get(3)?,
+            argv: row.get(4)?,
+            dir: row.get(5)?,
+            host: row.get(6)?,
+            count: row.get(7)?,
+            before: Vec::new(),
+            after: Vec::new(),
+            tags: Vec::new(),
+            note: None,
+            repo: None,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StatsQuery {
+    pub host: Option<String>,
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+    /// Number of rows in each of the "top N" lists
+    pub limit: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FailureRate {
+    pub argv: String,
+    pub runs: i64,
+    pub failures: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Stats {
+    pub total: i64,
+    pub top_commands: Vec<(String, i64)>,
+    pub top_programs: Vec<(String, i64)>,
+    pub top_dirs: Vec<(String, i64)>,
+    pub top_hosts: Vec<(String, i64)>,
+    pub failure_rates: Vec<FailureRate>,
+    /// (unix time the quarter hour starts at, count), only for quarter hours with any activity.
+    /// The client puts these into its own hours, weekdays and days, each at the UTC offset in
+    /// effect at the time, which no single offset sent with the query could do across a change
+    /// to or from daylight saving time. Every timezone is a whole number of quarter hours off
+    /// UTC.
+    pub by_quarter_hour: Vec<(i64, i64)>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct IngestCounters {
+    pub received: u64,
+    pub parsed: u64,
+    pub parse_failures: u64,
+    pub inserted: u64,
+    pub insert_failures: u64,
+    pub duplicates: u64,
+}
+
+impl From<&IngestStats> for IngestCounters {
+    fn from(stats: &IngestStats) -> IngestCounters {
+        let received = stats.received.load(Ordering::Relaxed);
+        let parse_failures = stats.parse_failures.load(Ordering::Relaxed);
+        IngestCounters {
+            received,
+            parsed: received.saturating_sub(parse_failures),
+            parse_failures,
+            inserted: stats.inserted.load(Ordering::Relaxed),
+            insert_failures: stats.insert_failures.load(Ordering::Relaxed),
+            duplicates: stats.duplicates.load(Ordering::Relaxed),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
-pub enum SqlType {
-    Int(i64),
-    String(String),
+pub struct ServerStatus {
+    pub version: String,
+    pub hostname: String,
+    pub pid: u32,
+    pub uptime_secs: u64,
+    pub db_path: String,
+    /// Size of the database file plus its write-ahead log
+    pub db_size_bytes: u64,
+    /// (table, number of rows)
+    pub row_counts: Vec<(String, i64)>,
+    /// Counts since the server started
+    pub ingest: IngestCounters,
+    /// Hosts that sent history in the last day, with the time of their latest entry
+    pub recent_hosts: Vec<(String, i64)>,
+    /// Set if this server is a replica that hasn't been promoted
+    pub replica: Option<ReplicaStatus>,
 }
 
 #[tarpc::service]
 pub trait HistoryQueryService {
-    async fn query(query: Query) -> core::result::Result<Vec<QueryResultRow>, RpcError>;
+    async fn query(query: Query) -> core::result::Result<QueryPage, RpcError>;
     async fn isearch(query: IsearchQuery) -> core::result::Result<Vec<QueryResultRow>, RpcError>;
+    async fn stats(query: StatsQuery) -> core::result::Result<Stats, RpcError>;
+    async fn status() -> core::result::Result<ServerStatus, RpcError>;
+    /// History rows with id greater than `after_id`, oldest first, for replicas to copy
+    async fn replicate(
+        after_id: i64,
+        limit: u32,
+    ) -> core::result::Result<Vec<(i64, RpcMessage)>, RpcError>;
+    /// Stop replicating and start accepting history
+    async fn promote() -> core::result::Result<(), RpcError>;
+    /// Merge the history database at `path`, on the server's filesystem, into this one
+    async fn merge(path: std::path::PathBuf) -> core::result::Result<MergeSummary, RpcError>;
+    /// Write a consistent copy of the database to `path`, on the server's filesystem
+    async fn backup(path: std::path::PathBuf) -> core::result::Result<BackupSummary, RpcError>;
+    /// Insert one entry, returning once it's committed. The reliable alternative to sending
+    /// it over UDP.
+    async fn insert(msg: RpcMessage) -> core::result::Result<(), RpcError>;
+    /// Tag the entry with id `id`
+    async fn add_tags(id: i64, tags: Vec<String>) -> core::result::Result<(), RpcError>;
+    /// Remove tags from the entry with id `id`
+    async fn remove_tags(id: i64, tags: Vec<String>) -> core::result::Result<(), RpcError>;
+    /// Set the note on the entry with id `id`, or remove it if `note` is None
+    async fn set_note(id: i64, note: Option<String>) -> core::result::Result<(), RpcError>;
+    /// Move the history in `old` and below to `new`, on `host` or on every host
+    async fn move_dir(
+        old: String,
+        new: String,
+        host: Option<String>,
+        dry_run: bool,
+    ) -> core::result::Result<MoveDirSummary, RpcError>;
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 struct HistoryQueryServerImpl {
-    con: Arc<Mutex<rusqlite::Connection>>,
+    db: Database,
+    metrics: Arc<Metrics>,
+    replica: Option<Arc<Replica>>,
+    in_flight: InFlight,
+    started: Instant,
+    /// Whether the connection came in on the local socket, so from the user running the server
+    local: bool,
+}
+
+/// RPCs being served, so that shutdown can wait for them to finish
+#[derive(Clone)]
+pub struct InFlight {
+    permits: Arc<Semaphore>,
+    /// Set by `close`, which ends every connection
+    closed: Arc<watch::Sender<bool>>,
+}
+
+const MAX_IN_FLIGHT: u32 = 1 << 16;
+
+impl InFlight {
+    fn new() -> InFlight {
+        InFlight {
+            permits: Arc::new(Semaphore::new(MAX_IN_FLIGHT as usize)),
+            closed: Arc::new(watch::channel(false).0),
+        }
+    }
+
+    /// Wait for the RPCs being served to finish. Any that arrive meanwhile wait behind this, and
+    /// no more can start until the returned permit is dropped.
+    pub async fn wait_idle(&self) -> Option<SemaphorePermit<'_>> {
+        self.permits.acquire_many(MAX_IN_FLIGHT).await.ok()
+    }
+
+    /// Turn away every RPC from now on, including those waiting behind `wait_idle`, and hang up
+    /// on the connections they came in on
+    pub fn close(&self) {
+        self.permits.close();
+        let _ = self.closed.send(true);
+    }
+}
+
+impl HistoryQueryServerImpl {
+    /// Count the call and how long it took, by RPC name. Also holds off shutdown until it's done.
+    async fn observe<T>(
+        &self,
+        rpc: &'static str,
+        fut: impl Future<Output = core::result::Result<T, RpcError>>,
+    ) -> core::result::Result<T, RpcError> {
+        let _permit = self
+            .in_flight
+            .permits
+            .acquire()
+            .await
+            .map_err(|_| RpcError::OtherError {
+                msg: "The server is shutting down".to_string(),
+            })?;
+        let start = Instant::now();
+        let result = fut.await;
+        self.metrics
+            .observe_rpc(rpc, start.elapsed(), result.is_ok());
+        result
+    }
+
+    /// A replica's database is a copy of its primary's, so only the primary takes changes
+    fn check_writable(&self) -> core::result::Result<(), RpcError> {
+        match self.replica.as_ref().and_then(|r| r.status()) {
+            Some(_) => Err(RpcError::OtherError {
+                msg: "This server is a replica, and doesn't accept changes until it's promoted"
+                    .to_string(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Admin RPCs change the server's role or touch its filesystem, so they're only taken from
+    /// the user running the server, over the local socket
+    fn check_admin(&self, rpc: &str) -> core::result::Result<(), RpcError> {
+        match self.local {
+            true => Ok(()),
+            false => Err(RpcError::OtherError {
+                msg: format!(
+                    "{} is only accepted over the server's local socket. Run it on the server's \
+                     host, as the user running the server.",
+                    rpc
+                ),
+            }),
+        }
+    }
 }
 
 #[tarpc::server]
@@ -109,185 +396,1054 @@ impl HistoryQueryService for HistoryQueryServerImpl {
         _ctx: context::Context,
         query: IsearchQuery,
     ) -> core::result::Result<Vec<QueryResultRow>, RpcError> {
-        let q = r#"
-        SELECT argv, dir, host, max(history.id)
+        let db = self.db.clone();
+        self.observe("isearch", db.read(move |con| run_isearch(con, query)))
+            .await
+    }
+
+    async fn stats(
+        self,
+        _ctx: context::Context,
+        query: StatsQuery,
+    ) -> core::result::Result<Stats, RpcError> {
+        let db = self.db.clone();
+        self.observe("stats", db.read(move |con| run_stats(con, query)))
+            .await
+    }
+
+    async fn query(
+        self,
+        _ctx: context::Context,
+        query: Query,
+    ) -> core::result::Result<QueryPage, RpcError> {
+        let db = self.db.clone();
+        self.observe("query", db.read(move |con| run_query(con, query)))
+            .await
+    }
+
+    async fn status(self, _ctx: context::Context) -> core::result::Result<ServerStatus, RpcError> {
+        let (row_counts, recent_hosts) = self.observe("status", self.db.read(run_status)).await?;
+        Ok(ServerStatus {
+            version: crate::VERSION.to_string(),
+            hostname: crate::MYHOSTNAME.clone(),
+            pid: std::process::id(),
+            uptime_secs: self.started.elapsed().as_secs(),
+            db_path: self.db.path().display().to_string(),
+            db_size_bytes: self.db.size_bytes(),
+            row_counts,
+            ingest: IngestCounters::from(&*self.metrics.ingest),
+            recent_hosts,
+            replica: self.replica.as_ref().and_then(|r| r.status()),
+        })
+    }
+
+    async fn replicate(
+        self,
+        _ctx: context::Context,
+        after_id: i64,
+        limit: u32,
+    ) -> core::result::Result<Vec<(i64, RpcMessage)>, RpcError> {
+        let db = self.db.clone();
+        self.observe(
+            "replicate",
+            db.read(move |con| run_replicate(con, after_id, limit)),
+        )
+        .await
+    }
+
+    async fn promote(self, _ctx: context::Context) -> core::result::Result<(), RpcError> {
+        self.check_admin("promote")?;
+        let replica = self.replica.clone();
+        self.observe("promote", async move {
+            match replica.map(|r| r.promote()) {
+                Some(true) => Ok(()),
+                _ => Err(RpcError::OtherError {
+                    msg: "This server isn't a replica".to_string(),
+                }),
+            }
+        })
+        .await
+    }
+
+    async fn merge(
+        self,
+        _ctx: context::Context,
+        path: std::path::PathBuf,
+    ) -> core::result::Result<MergeSummary, RpcError> {
+        self.check_admin("merge")?;
+        self.check_writable()?;
+        if !path.is_absolute() || !path.is_file() {
+            return Err(RpcError::InvalidFilename { path });
+        }
+        let db = self.db.clone();
+        self.observe(
+            "merge",
+            db.write(move |con| merge(con, &path).map_err(RpcError::from)),
+        )
+        .await
+    }
+
+    async fn backup(
+        self,
+        _ctx: context::Context,
+        path: std::path::PathBuf,
+    ) -> core::result::Result<BackupSummary, RpcError> {
+        self.check_admin("backup")?;
+        if !path.is_absolute() || path.is_dir() {
+            return Err(RpcError::InvalidFilename { path });
+        }
+        let db = self.db.clone();
+        self.observe(
+            "backup",
+            db.read(move |con| backup(con, &path).map_err(RpcError::from)),
+        )
+        .await
+    }
+
+    async fn insert(
+        self,
+        _ctx: context::Context,
+        msg: RpcMessage,
+    ) -> core::result::Result<(), RpcError> {
+        self.check_writable()?;
+        let stats = self.metrics.ingest.clone();
+        stats.received.fetch_add(1, Ordering::Relaxed);
+        let db = self.db.clone();
+        let result = self
+            .observe(
+                "insert",
+                db.write(move |con| insert(con, &msg, None).map_err(RpcError::from)),
+            )
+            .await;
+        match &result {
+            Ok(false) => {
+                stats.duplicates.fetch_add(1, Ordering::Relaxed);
+            }
+            Ok(true) => {
+                stats.inserted.fetch_add(1, Ordering::Relaxed);
+                stats
+                    .last_insert
+                    .store(chrono::Utc::now().timestamp() as u64, Ordering::Relaxed);
+            }
+            Err(_) => {
+                stats.insert_failures.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        result.map(|_| ())
+    }
+
+    async fn add_tags(
+        self,
+        _ctx: context::Context,
+        id: i64,
+        tags: Vec<String>,
+    ) -> core::result::Result<(), RpcError> {
+        self.check_writable()?;
+        let db = self.db.clone();
+        self.observe(
+            "add_tags",
+            db.write(move |con| add_tags(con, id, &tags).map_err(RpcError::from)),
+        )
+        .await
+        .map(|_| ())
+    }
+
+    async fn remove_tags(
+        self,
+        _ctx: context::Context,
+        id: i64,
+        tags: Vec<String>,
+    ) -> core::result::Result<(), RpcError> {
+        self.check_writable()?;
+        let db = self.db.clone();
+        self.observe(
+            "remove_tags",
+            db.write(move |con| remove_tags(con, id, &tags).map_err(RpcError::from)),
+        )
+        .await
+        .map(|_| ())
+    }
+
+    async fn set_note(
+        self,
+        _ctx: context::Context,
+        id: i64,
+        note: Option<String>,
+    ) -> core::result::Result<(), RpcError> {
+        self.check_writable()?;
+        let db = self.db.clone();
+        self.observe(
+            "set_note",
+            db.write(move |con| set_note(con, id, note.as_deref()).map_err(RpcError::from)),
+        )
+        .await
+    }
+
+    async fn move_dir(
+        self,
+        _ctx: context::Context,
+        old: String,
+        new: String,
+        host: Option<String>,
+        dry_run: bool,
+    ) -> core::result::Result<MoveDirSummary, RpcError> {
+        self.check_writable()?;
+        let db = self.db.clone();
+        self.observe(
+            "move_dir",
+            db.write(move |con| {
+                move_dir(con, &old, &new, host.as_deref(), dry_run).map_err(RpcError::from)
+            }),
+        )
+        .await
+    }
+}
+
+fn run_replicate(
+    con: &rusqlite::Connection,
+    after_id: i64,
+    limit: u32,
+) -> core::result::Result<Vec<(i64, RpcMessage)>, RpcError> {
+    let mut stmt = con.prepare_cached(
+        "
+        SELECT history.id, host, history.session_id, history.session, exit_status, dir, argv, end_time,
+            history.history_number
+        FROM history
+        JOIN commands ON history.command_id = commands.id
+        JOIN places ON history.place_id = places.id
+        WHERE history.id > ?
+        ORDER BY history.id
+        LIMIT ?
+        ",
+    )?;
+    let mut rows = stmt
+        .query_map(
+            [after_id, limit.min(REPLICATION_BATCH_SIZE) as i64],
+            |row| {
+                Ok((
+                    row.get(0)?,
+                    RpcMessage {
+                        host: row.get(1)?,
+                        session: row.get(2)?,
+                        tty: row.get(3)?,
+                        exit_status: row.get(4)?,
+                        dir: row.get(5)?,
+                        argv: row.get(6)?,
+                        time: row.get(7)?,
+                        history_number: row.get(8)?,
+                        context: Vec::new(),
+                    },
+                ))
+            },
+        )?
+        .collect::<rusqlite::Result<Vec<(i64, RpcMessage)>>>()?;
+    let mut context = con.prepare_cached("SELECT key, value FROM context WHERE history_id = ?")?;
+    for (id, msg) in rows.iter_mut() {
+        msg.context = context
+            .query_map([*id], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+    }
+    Ok(rows)
+}
+
+type RowCounts = Vec<(String, i64)>;
+type RecentHosts = Vec<(String, i64)>;
+
+fn run_status(
+    con: &rusqlite::Connection,
+) -> core::result::Result<(RowCounts, RecentHosts), RpcError> {
+    let mut row_counts = Vec::new();
+    for table in ["history", "commands", "places"] {
+        let n = con.query_row(&format!("SELECT count(*) FROM {table}"), [], |row| {
+            row.get(0)
+        })?;
+        row_counts.push((table.to_string(), n));
+    }
+
+    let mut stmt = con.prepare(
+        "
+        SELECT host, max(end_time)
         FROM history
-        JOIN commands on history.command_id = commands.id
         JOIN places on history.place_id = places.id
-        WHERE argv LIKE ('%' || :argv || '%') ESCAPE '\'
-        GROUP BY history.command_id, history.place_id
-        ORDER BY
-            dir LIKE (:dir || '%') ESCAPE '\' DESC,
-            argv LIKE (:argv || '%') ESCAPE '\' DESC,
-            max(history.id) DESC
-        LIMIT :limit
-        OFFSET :offset;
-        "#;
-        let like_escape = |s: &str| ToSqlOutput::from(s.replace("%", "\\%").replace("_", "\\_"));
-
-        let con = self.con.lock().await;
-        let params = named_params! {
-            ":argv": like_escape(&query.command),
-            ":dir": like_escape(&query.dir),
-            ":limit": query.limit.to_sql()?,
-            ":offset": query.offset.to_sql()?,
-        };
+        WHERE end_time >= CAST(strftime('%s', 'now') AS INTEGER) - 24 * 60 * 60
+        GROUP BY host
+        ORDER BY max(end_time) DESC
+        ",
+    )?;
+    let recent_hosts = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok((row_counts, recent_hosts))
+}
 
-        let mut stmt = con.prepare(q)?;
-        let mut rows = stmt.query(params)?;
+fn run_isearch(
+    con: &rusqlite::Connection,
+    query: IsearchQuery,
+) -> core::result::Result<Vec<QueryResultRow>, RpcError> {
+    let q = r#"
+    SELECT argv, dir, host, max(history.id)
+    FROM history
+    JOIN commands on history.command_id = commands.id
+    JOIN places on history.place_id = places.id
+    WHERE argv LIKE ('%' || :argv || '%') ESCAPE '\'
+      AND (:include_failures OR NOT :hide_not_found OR history.exit_status IS NOT 127)
+    GROUP BY history.command_id, history.place_id
+    ORDER BY
+        -- exit status > 128 means killed by a signal (e.g. ctrl-c on `tail -f`), which
+        -- isn't the command's fault, so only penalize commands that never succeeded. They go
+        -- below every command that did, even those run in other directories.
+        (:include_failures OR max(history.exit_status = 0 OR history.exit_status > 128)) DESC,
+        dir LIKE (:dir || '%') ESCAPE '\' DESC,
+        argv LIKE (:argv || '%') ESCAPE '\' DESC,
+        max(history.id) DESC
+    LIMIT :limit
+    OFFSET :offset;
+    "#;
+    let like_escape = |s: &str| ToSqlOutput::from(s.replace("%", "\\%").replace("_", "\\_"));
+
+    let params = named_params! {
+        ":argv": like_escape(&query.command),
+        ":dir": like_escape(&query.dir),
+        ":limit": query.limit.to_sql()?,
+        ":offset": query.offset.to_sql()?,
+        ":include_failures": query.include_failures.to_sql()?,
+        ":hide_not_found": query.hide_not_found.to_sql()?,
+    };
+
+    let mut stmt = con.prepare(q)?;
+    let mut rows = stmt.query(params)?;
+    let mut result = Vec::new();
+    while let Some(row) = rows.next()? {
+        result.push(QueryResultRow {
+            id: row.get(3)?,
+            argv: row.get(0)?,
+            time: row.get(3)?,
+            session: None,
+            tty: None,
+            dir: row.get(1)?,
+            host: row.get(2)?,
+            count: 1,
+            before: Vec::new(),
+            after: Vec::new(),
+            tags: Vec::new(),
+            note: None,
+            repo: None,
+        });
+    }
+    for row in result.iter_mut() {
+        (row.tags, row.note) = annotations(con, row.id, &["command_id", "place_id"])?;
+    }
+
+    Ok(result)
+}
+
+fn run_stats(
+    con: &rusqlite::Connection,
+    query: StatsQuery,
+) -> core::result::Result<Stats, RpcError> {
+    debug!("Received stats query");
+    let filter = r#"
+        FROM history
+        JOIN commands on history.command_id = commands.id
+        JOIN places on history.place_id = places.id
+        WHERE (:host IS NULL OR places.host = :host)
+          AND (:since IS NULL OR history.end_time >= :since)
+          AND (:until IS NULL OR history.end_time <= :until)
+    "#;
+    let params = named_params! {
+        ":host": query.host,
+        ":since": query.since,
+        ":until": query.until,
+        ":limit": query.limit,
+    };
+    // binds only the parameters that appear in each statement, since sqlite rejects extras
+    let run = |sql: &str| -> rusqlite::Result<Vec<(String, i64, i64)>> {
+        let mut stmt = con.prepare(sql)?;
+        for (name, value) in params {
+            if let Some(i) = stmt.parameter_index(name)? {
+                stmt.raw_bind_parameter(i, value)?;
+            }
+        }
+        let mut rows = stmt.raw_query();
         let mut result = Vec::new();
         while let Some(row) = rows.next()? {
-            result.push(QueryResultRow {
-                argv: row.get(0)?,
-                time: row.get(3)?,
-                session: 0,
-                dir: row.get(1)?,
-                host: row.get(2)?,
-            });
+            result.push((row.get(0)?, row.get(1)?, row.get(2)?));
         }
-
         Ok(result)
+    };
+    let counts = |select: &str, rest: &str| -> rusqlite::Result<Vec<(String, i64)>> {
+        Ok(
+            run(&format!("SELECT {select}, count(*), 0 {filter} {rest}"))?
+                .into_iter()
+                .map(|(key, n, _)| (key, n))
+                .collect(),
+        )
+    };
+    let top = |select: &str| counts(select, "GROUP BY 1 ORDER BY count(*) DESC, 1 LIMIT :limit");
+
+    let total = counts("''", "")?.first().map(|x| x.1).unwrap_or(0);
+    let top_commands = top("argv")?;
+    // the program is the first word of the command line
+    let top_programs = top("CASE WHEN instr(ltrim(argv), ' ') > 0
+            THEN substr(ltrim(argv), 1, instr(ltrim(argv), ' ') - 1)
+            ELSE ltrim(argv) END")?;
+    let top_dirs = top("dir")?;
+    let top_hosts = top("host")?;
+
+    let mut by_quarter_hour = Vec::new();
+    for (start, n) in counts(
+        "CAST(history.end_time / 900 * 900 AS TEXT)",
+        "GROUP BY history.end_time / 900",
+    )? {
+        by_quarter_hour.push((start.parse().map_err(anyhow::Error::from)?, n));
     }
 
-    async fn query(
-        self,
-        _ctx: context::Context,
-        query: Query,
-    ) -> core::result::Result<Vec<QueryResultRow>, RpcError> {
-        let Query {
-            host,
-            command,
-            exact,
-            indir,
-            atdir,
-            session,
-            status,
-            since,
-            until,
-            desc,
-            limit,
-        } = query;
-
-        debug!("Received query");
-        let (hostwhere, hostwhereparams) = match host.as_ref() {
-            Some(h) => ("places.host = ?", Some(h.to_sql()?)),
-            None => ("1", None),
-        };
-        let (commandwhere, commandwhereparams) = match (command.as_ref(), exact) {
+    let failure_rates = run(&format!(
+        "SELECT argv, count(*), sum(history.exit_status != 0)
+        {filter}
+        GROUP BY history.command_id
+        HAVING sum(history.exit_status != 0) > 0
+        ORDER BY sum(history.exit_status != 0) DESC, count(*) DESC
+        LIMIT :limit"
+    ))?
+    .into_iter()
+    .map(|(argv, runs, failures)| FailureRate {
+        argv,
+        runs,
+        failures,
+    })
+    .collect();
+
+    Ok(Stats {
+        total,
+        top_commands,
+        top_programs,
+        top_dirs,
+        top_hosts,
+        failure_rates,
+        by_quarter_hour,
+    })
+}
+
+/// The names a query's filter refers to the history, commands and places tables by
+#[derive(Clone, Copy)]
+struct Tables {
+    history: &'static str,
+    commands: &'static str,
+    places: &'static str,
+}
+
+impl Tables {
+    const MAIN: Tables = Tables {
+        history: "history",
+        commands: "commands",
+        places: "places",
+    };
+    /// The second copy of the tables, that the grouped query counts and compares executions in
+    const OTHERS: Tables = Tables {
+        history: "h",
+        commands: "c",
+        places: "p",
+    };
+}
+
+fn run_query(
+    con: &rusqlite::Connection,
+    query: Query,
+) -> core::result::Result<QueryPage, RpcError> {
+    let Query {
+        host,
+        command,
+        exact,
+        indir,
+        atdir,
+        session,
+        tty,
+        status,
+        since,
+        until,
+        limit,
+        cursor,
+        grouping,
+        before,
+        after,
+        context,
+        tags,
+    } = query;
+
+    debug!("Received query");
+    // each condition names the tables through `Tables`, since the grouped query applies the
+    // filter to a second copy of them, under other names
+    let (hostwhere, hostwhereparams): (fn(Tables) -> String, _) = match host.as_ref() {
+        Some(h) => (|t| format!("{}.host = ?", t.places), Some(h.to_sql()?)),
+        None => (|_| "1".to_string(), None),
+    };
+    let (commandwhere, commandwhereparams): (fn(Tables) -> String, _) =
+        match (command.as_ref(), exact) {
             (Some(cmd), false) => (
-                "commands.argv GLOB ?",
+                |t| format!("{}.argv GLOB ?", t.commands),
                 Some(ToSqlOutput::from(format!("*{}*", cmd))),
             ),
-            (Some(cmd), true) => ("commands.argv = ?", Some(cmd.to_sql()?)),
-            _ => ("1", None),
-        };
-        let (indirwhere, indirwhereparams) = match indir.as_ref() {
-            Some(indir) => (
-                "places.dir LIKE ?",
-                Some(ToSqlOutput::from(format!("{}%", indir))),
-            ),
-            None => ("1", None),
-        };
-        let (atdirwhere, atdirwhereparams) = match atdir.as_ref() {
-            Some(atdir) => ("places.dir = ?", Some(atdir.to_sql()?)),
-            None => ("1", None),
-        };
-        let (sessionwhere, sessionwhereparams) = match session.as_ref() {
-            Some(session) => ("session = ?", Some(session.to_sql()?)),
-            None => ("1", None),
-        };
-        let (statuswhere, statuswhereparams) = match status.as_ref() {
-            Some(x) if x == "error" => ("history.exit_status > 0", None),
-            Some(x) => ("cast(history.exit_status as str) = ?", Some(x.to_sql()?)),
-            None => ("1", None),
+            (Some(cmd), true) => (|t| format!("{}.argv = ?", t.commands), Some(cmd.to_sql()?)),
+            _ => (|_| "1".to_string(), None),
         };
-        let (sincewhere, sincewhereparams) = match since.as_ref() {
-            Some(x) => ("history.end_time >= ?", Some(x.to_sql()?)),
-            None => ("1", None),
-        };
-        let (untilwhere, untilwhereparams) = match until.as_ref() {
-            Some(x) => ("history.end_time <= ?", Some(x.to_sql()?)),
-            None => ("1", None),
-        };
-        let query = format!(
-            "
-            SELECT end_time, session, argv, dir, host, max(end_time) as max_time
-            FROM commands
-            JOIN history on history.command_id = commands.id
-            JOIN places on history.place_id = places.id
-            WHERE {hostwhere}
-              AND {commandwhere}
-              AND {indirwhere}
-              AND {atdirwhere}
-              AND {sessionwhere}
-              AND {statuswhere}
-              AND {sincewhere}
-              AND {untilwhere}
-            GROUP BY history.command_id, history.place_id
-            ORDER BY max_time DESC
-            LIMIT {limit}
-        "
-        );
-        let paramv = vec![
-            hostwhereparams,
-            commandwhereparams,
-            indirwhereparams,
-            atdirwhereparams,
-            sessionwhereparams,
-            statuswhereparams,
-            sincewhereparams,
-            untilwhereparams,
+    let (indirwhere, indirwhereparams): (fn(Tables) -> String, _) = match indir.as_ref() {
+        Some(indir) => (
+            |t| format!("{}.dir LIKE ?", t.places),
+            Some(ToSqlOutput::from(format!("{}%", indir))),
+        ),
+        None => (|_| "1".to_string(), None),
+    };
+    let (atdirwhere, atdirwhereparams): (fn(Tables) -> String, _) = match atdir.as_ref() {
+        Some(atdir) => (|t| format!("{}.dir = ?", t.places), Some(atdir.to_sql()?)),
+        None => (|_| "1".to_string(), None),
+    };
+    let (sessionwhere, sessionwhereparams): (fn(Tables) -> String, _) = match session.as_ref() {
+        Some(session) => (
+            |t| format!("{}.session_id = ?", t.history),
+            Some(session.to_sql()?),
+        ),
+        None => (|_| "1".to_string(), None),
+    };
+    let (ttywhere, ttywhereparams): (fn(Tables) -> String, _) = match tty.as_ref() {
+        Some(tty) => (
+            |t| format!("{}.session = ?", t.history),
+            Some(tty.to_sql()?),
+        ),
+        None => (|_| "1".to_string(), None),
+    };
+    let (statuswhere, statuswhereparams): (fn(Tables) -> String, _) = match status.as_ref() {
+        Some(x) if x == "error" => (|t| format!("{}.exit_status > 0", t.history), None),
+        Some(x) => (
+            |t| format!("cast({}.exit_status as str) = ?", t.history),
+            Some(x.to_sql()?),
+        ),
+        None => (|_| "1".to_string(), None),
+    };
+    let (sincewhere, sincewhereparams): (fn(Tables) -> String, _) = match since.as_ref() {
+        Some(x) => (
+            |t| format!("{}.end_time >= ?", t.history),
+            Some(x.to_sql()?),
+        ),
+        None => (|_| "1".to_string(), None),
+    };
+    let (untilwhere, untilwhereparams): (fn(Tables) -> String, _) = match until.as_ref() {
+        Some(x) => (
+            |t| format!("{}.end_time <= ?", t.history),
+            Some(x.to_sql()?),
+        ),
+        None => (|_| "1".to_string(), None),
+    };
+    let n_context = context.len();
+    let contextwhere = |t: Tables| match n_context {
+        0 => "1".to_string(),
+        n => vec![
+            format!(
+                "EXISTS (SELECT 1 FROM context
+                WHERE context.history_id = {}.id AND context.key = ? AND context.value = ?)",
+                t.history
+            );
+            n
         ]
+        .join(" AND "),
+    };
+    let contextwhereparams = context
         .into_iter()
-        .flatten();
-        let params = params_from_iter(paramv);
-        let con = self.con.lock().await;
-        let mut stmt = con.prepare(&query)?;
-        let mut rows = stmt.query(params)?;
-        let mut result = Vec::new();
-        while let Some(row) = rows.next()? {
-            result.push(QueryResultRow {
-                time: row.get(0)?,
-                session: row.get(1)?,
-                argv: row.get(2)?,
-                dir: row.get(3)?,
-                host: row.get(4)?,
-            });
+        .flat_map(|(key, value)| [ToSqlOutput::from(key), ToSqlOutput::from(value)]);
+    let n_tags = tags.len();
+    let tagwhere = |t: Tables| match n_tags {
+        0 => "1".to_string(),
+        n => vec![
+            format!(
+                "EXISTS (SELECT 1 FROM tags WHERE tags.history_id = {}.id AND tags.tag = ?)",
+                t.history
+            );
+            n
+        ]
+        .join(" AND "),
+    };
+    let tagwhereparams = tags.into_iter().map(ToSqlOutput::from);
+    // a negative limit means no limit, a page at a time
+    let limit = match limit {
+        0..=MAX_PAGE_SIZE => limit,
+        _ => MAX_PAGE_SIZE,
+    };
+    let conditions_on = |t: Tables| {
+        format!(
+            "
+            {}
+          AND {}
+          AND {}
+          AND {}
+          AND {}
+          AND {}
+          AND {}
+          AND {}
+          AND {}
+          AND {}
+          AND {}
+        ",
+            hostwhere(t),
+            commandwhere(t),
+            indirwhere(t),
+            atdirwhere(t),
+            sessionwhere(t),
+            ttywhere(t),
+            statuswhere(t),
+            sincewhere(t),
+            untilwhere(t),
+            contextwhere(t),
+            tagwhere(t),
+        )
+    };
+    let conditions = conditions_on(Tables::MAIN);
+    let conditionparams: Vec<ToSqlOutput> = vec![
+        hostwhereparams,
+        commandwhereparams,
+        indirwhereparams,
+        atdirwhereparams,
+        sessionwhereparams,
+        ttywhereparams,
+        statuswhereparams,
+        sincewhereparams,
+        untilwhereparams,
+    ]
+    .into_iter()
+    .flatten()
+    .chain(contextwhereparams)
+    .chain(tagwhereparams)
+    .collect();
+    let columns =
+        "history.id, history.end_time, history.session, history.session_id, argv, dir, host";
+    let (same_group, group_columns): (_, &[&str]) = match grouping {
+        Grouping::CommandPlace => (
+            Some("h.command_id = history.command_id AND h.place_id = history.place_id"),
+            &["command_id", "place_id"],
+        ),
+        Grouping::Command => (Some("h.command_id = history.command_id"), &["command_id"]),
+        Grouping::None => (None, &[]),
+    };
+    let (cursorwhere, cursorwhereparams) = match cursor {
+        Some(Cursor { time, id }) => ("(history.end_time, history.id) < (?, ?)", vec![time, id]),
+        None => ("1", vec![]),
+    };
+    // results are read off the end_time index from the cursor on, so that each page costs the
+    // same however deep into the results it is
+    let (query, paramv) = match same_group {
+        // the representative for each group is its latest execution, with the id breaking
+        // ties between executions that finished in the same second. the other executions in
+        // the group are found through the (command_id, place_id, end_time) index
+        Some(same_group) => {
+            let others = format!(
+                "
+                FROM history h
+                JOIN commands c ON h.command_id = c.id
+                JOIN places p ON h.place_id = p.id
+                WHERE {same_group} AND {}
+                ",
+                conditions_on(Tables::OTHERS)
+            );
+            let query = format!(
+                "
+                SELECT {columns}, (SELECT count(*) {others}) AS count
+                FROM commands
+                JOIN history on history.command_id = commands.id
+                JOIN places on history.place_id = places.id
+                WHERE {conditions}
+                  AND NOT EXISTS (
+                      SELECT 1 {others}
+                        AND h.end_time >= history.end_time
+                        AND (h.end_time, h.id) > (history.end_time, history.id)
+                  )
+                  AND {cursorwhere}
+                ORDER BY history.end_time DESC, history.id DESC
+                LIMIT {limit}
+                "
+            );
+            // in the order the placeholders appear: the count, the filter, the newer executions
+            let paramv = [&conditionparams, &conditionparams, &conditionparams]
+                .into_iter()
+                .flatten()
+                .cloned()
+                .collect::<Vec<_>>();
+            (query, paramv)
         }
-
-        if !desc {
-            result.reverse();
+        None => (
+            format!(
+                "
+                SELECT {columns}, 1
+                FROM commands
+                JOIN history on history.command_id = commands.id
+                JOIN places on history.place_id = places.id
+                WHERE {conditions}
+                  AND {cursorwhere}
+                ORDER BY history.end_time DESC, history.id DESC
+                LIMIT {limit}
+                "
+            ),
+            conditionparams,
+        ),
+    };
+    let params = params_from_iter(
+        paramv
+            .into_iter()
+            .chain(cursorwhereparams.into_iter().map(ToSqlOutput::from)),
+    );
+    let mut stmt = con.prepare(&query)?;
+    let mut rows = stmt.query(params)?;
+    let mut result = Vec::new();
+    while let Some(row) = rows.next()? {
+        result.push(QueryResultRow::from_sql(row)?);
+    }
+    for hit in result.iter_mut() {
+        (hit.tags, hit.note) = annotations(con, hit.id, group_columns)?;
+        hit.repo = repo_root(con, hit.id)?;
+        if before > 0 || after > 0 {
+            hit.before = neighbors(con, hit, before, Direction::Before)?;
+            hit.after = neighbors(con, hit, after, Direction::After)?;
         }
+    }
 
-        debug!("Returned response. {} rows", result.len());
-        Ok(result)
+    let next = match result.last() {
+        Some(last) if result.len() as i32 == limit => Some(Cursor {
+            time: last.time,
+            id: last.id,
+        }),
+        _ => None,
+    };
+
+    debug!("Returned response. {} rows", result.len());
+    Ok(QueryPage { rows: result, next })
+}
+
+/// The checkout root recorded with the entry `id`, as the `repo` context field
+fn repo_root(con: &rusqlite::Connection, id: i64) -> rusqlite::Result<Option<String>> {
+    con.prepare_cached("SELECT value FROM context WHERE history_id = ? AND key = 'repo'")?
+        .query_row([id], |row| row.get(0))
+        .optional()
+}
+
+enum Direction {
+    Before,
+    After,
+}
+
+/// Up to `n` entries run just before or after `hit` in the same shell on the same host, oldest
+/// first.
+fn neighbors(
+    con: &rusqlite::Connection,
+    hit: &QueryResultRow,
+    n: u32,
+    direction: Direction,
+) -> rusqlite::Result<Vec<QueryResultRow>> {
+    if n == 0 {
+        return Ok(Vec::new());
     }
+    let (cmp, order) = match direction {
+        Direction::Before => ("<", "DESC"),
+        Direction::After => (">", "ASC"),
+    };
+    // entries from shells that predate unique session ids can only be matched up by tty
+    let q = format!(
+        "
+        SELECT history.id, end_time, history.session, history.session_id, argv, dir, host, 1
+        FROM history
+        JOIN commands on history.command_id = commands.id
+        JOIN places on history.place_id = places.id
+        WHERE places.host = :host
+          AND history.session_id IS :session
+          AND (:session IS NOT NULL OR history.session IS :tty)
+          AND (history.end_time, history.id) {cmp} (:time, :id)
+        ORDER BY history.end_time {order}, history.id {order}
+        LIMIT :n
+        "
+    );
+    let mut stmt = con.prepare(&q)?;
+    let rows = stmt.query_map(
+        named_params! {
+            ":host": hit.host,
+            ":session": hit.session,
+            ":tty": hit.tty,
+            ":time": hit.time,
+            ":id": hit.id,
+            ":n": n,
+        },
+        QueryResultRow::from_sql,
+    )?;
+    let mut result = rows.collect::<rusqlite::Result<Vec<_>>>()?;
+    for row in result.iter_mut() {
+        (row.tags, row.note) = annotations(con, row.id, &[])?;
+        row.repo = repo_root(con, row.id)?;
+    }
+    if let Direction::Before = direction {
+        result.reverse();
+    }
+    Ok(result)
 }
+
 pub struct HistoryQueryServer {
-    con: Arc<Mutex<rusqlite::Connection>>,
+    db: Database,
+    metrics: Arc<Metrics>,
+    replica: Option<Arc<Replica>>,
+    in_flight: InFlight,
+    started: Instant,
 }
 impl HistoryQueryServer {
-    pub fn new(con: Arc<Mutex<rusqlite::Connection>>) -> HistoryQueryServer {
-        HistoryQueryServer { con }
+    pub fn new(
+        db: Database,
+        metrics: Arc<Metrics>,
+        replica: Option<Arc<Replica>>,
+    ) -> HistoryQueryServer {
+        HistoryQueryServer {
+            db,
+            metrics,
+            replica,
+            in_flight: InFlight::new(),
+            started: Instant::now(),
+        }
+    }
+    pub fn in_flight(&self) -> InFlight {
+        self.in_flight.clone()
     }
-    pub async fn run(self) -> Result<()> {
-        let addr = format!("0.0.0.0:{}", crate::HISTORY_PORT);
-        let mut incoming = tarpc::serde_transport::tcp::listen(&addr, Bincode::default).await?;
+    /// Serve queries from wherever `listen` says. Admin RPCs are only taken over the local
+    /// socket, so a server on the network listens there too, when it can.
+    pub async fn run(self, listen: Listen) -> Result<()> {
+        let (bind, allowlist) = match listen {
+            Listen::Network { bind, allowlist } => (bind, allowlist),
+            Listen::Local => return self.serve_local().await,
+        };
+        let admin = async {
+            if let Err(e) = self.serve_local().await {
+                warn!(
+                    "{:#}. Admin commands like `history promote` won't reach this server",
+                    e
+                );
+            }
+            futures_util::future::pending().await
+        };
+
+        let network = async {
+            let addr = SocketAddr::new(bind, crate::HISTORY_PORT);
+            let mut incoming = tarpc::serde_transport::tcp::listen(&addr, Bincode::default)
+                .await
+                .with_context(|| format!("Unable to bind {}", addr))?;
+            loop {
+                if let Some(x) = incoming.next().await {
+                    match x {
+                        Ok(transport) => match transport.peer_addr() {
+                            Ok(peer) if allowlist.allows(peer.ip(), "query connection") => {
+                                self.serve_connection(transport, false)
+                            }
+                            Ok(_) => {}
+                            // without a peer, there's nothing to check against the allowlist
+                            Err(e) => {
+                                error!("Dropped query connection from an unknown peer: {}", e)
+                            }
+                        },
+                        Err(e) => {
+                            error!("{}", e)
+                        }
+                    };
+                }
+            }
+        };
+        tokio::select! {
+            r = admin => r,
+            r = network => r,
+        }
+    }
+    /// Serve queries on the local socket, which only this user can connect to
+    async fn serve_local(&self) -> Result<()> {
+        let path = query_socket_path()?;
+        // the pidfile only keeps out servers for the same database
+        if UnixStream::connect(&path).await.is_ok() {
+            anyhow::bail!(
+                "Another history server is already serving {}",
+                path.display()
+            );
+        }
+        prepare_socket_path(&path)?;
+        let listener = UnixListener::bind(&path)
+            .with_context(|| format!("Unable to bind {}", path.display()))?;
+        restrict_socket(&path)?;
+        info!("Serving queries on {}", path.display());
         loop {
-            if let Some(x) = incoming.next().await {
-                match x {
-                    Ok(transport) => {
-                        let server = HistoryQueryServerImpl {
-                            con: self.con.clone(),
-                        };
-                        let fut = BaseChannel::with_defaults(transport).execute(server.serve());
-                        tokio::spawn(fut);
-                    }
-                    Err(e) => {
-                        error!("{}", e)
-                    }
-                };
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    self.serve_connection(Transport::from((stream, Bincode::default())), true)
+                }
+                Err(e) => error!("{}", e),
             }
         }
     }
+    fn serve_connection<T>(&self, transport: T, local: bool)
+    where
+        T: tarpc::Transport<
+                tarpc::Response<HistoryQueryServiceResponse>,
+                tarpc::ClientMessage<HistoryQueryServiceRequest>,
+            > + Send
+            + 'static,
+    {
+        let server = HistoryQueryServerImpl {
+            db: self.db.clone(),
+            metrics: self.metrics.clone(),
+            replica: self.replica.clone(),
+            in_flight: self.in_flight.clone(),
+            started: self.started,
+            local,
+        };
+        let fut = BaseChannel::with_defaults(transport).execute(server.serve());
+        let mut closed = self.in_flight.closed.subscribe();
+        // each request is handled in a task of its own, so hanging up doesn't cut any short
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = fut => {}
+                _ = closed.changed() => {}
+            }
+        });
+    }
+}
+
+#[test]
+fn test_grouped_paging() {
+    use crate::udp::{insert, test_entry, RpcMessage};
+
+    let con = rusqlite::Connection::open_in_memory().unwrap();
+    crate::schema::create_schema(&con).unwrap();
+    // make runs at 1, 4, 7; ls at 2, 5; cargo at 3, failing at 6
+    let runs = [
+        ("make", 0),
+        ("ls", 0),
+        ("cargo", 0),
+        ("make", 0),
+        ("ls", 0),
+        ("cargo", 1),
+        ("make", 1),
+    ];
+    for (i, (argv, exit_status)) in runs.into_iter().enumerate() {
+        let msg = RpcMessage {
+            exit_status,
+            ..test_entry("/", argv, i as u64 + 1)
+        };
+        insert(&con, &msg, None).unwrap();
+    }
+    let query = |status: Option<&str>, limit, cursor| Query {
+        host: None,
+        command: None,
+        exact: false,
+        indir: None,
+        atdir: None,
+        session: None,
+        tty: None,
+        status: status.map(str::to_string),
+        since: None,
+        until: None,
+        limit,
+        cursor,
+        grouping: Grouping::CommandPlace,
+        before: 0,
+        after: 0,
+        context: Vec::new(),
+        tags: Vec::new(),
+    };
+    let rows = |page: &QueryPage| {
+        page.rows
+            .iter()
+            .map(|r| (r.argv.clone(), r.time, r.count))
+            .collect::<Vec<_>>()
+    };
+    let expected = |x: &[(&str, i64, i64)]| {
+        x.iter()
+            .map(|&(argv, time, count)| (argv.to_string(), time, count))
+            .collect::<Vec<_>>()
+    };
+
+    let first = run_query(&con, query(None, 2, None)).unwrap();
+    assert_eq!(rows(&first), expected(&[("make", 7, 3), ("cargo", 6, 2)]));
+    let second = run_query(&con, query(None, 2, first.next)).unwrap();
+    assert_eq!(rows(&second), expected(&[("ls", 5, 2)]));
+    assert!(second.next.is_none());
+
+    // the groups are formed from the matching executions only
+    let succeeded = run_query(&con, query(Some("0"), -1, None)).unwrap();
+    assert_eq!(
+        rows(&succeeded),
+        expected(&[("ls", 5, 2), ("make", 4, 2), ("cargo", 3, 1)])
+    );
+    // and the filter on commands and places applies to the executions counted too
+    let filtered = Query {
+        command: Some("ma".to_string()),
+        indir: Some("/".to_string()),
+        ..query(Some("0"), -1, None)
+    };
+    assert_eq!(
+        rows(&run_query(&con, filtered).unwrap()),
+        expected(&[("make", 4, 2)])
+    );
+}
+
+#[tokio::test]
+async fn test_merge_refused_on_replica() {
+    let dir = std::env::temp_dir().join(format!("history-test-replica-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let other = dir.join("other.db");
+    crate::schema::create_schema(&rusqlite::Connection::open(&other).unwrap()).unwrap();
+    let server = HistoryQueryServerImpl {
+        db: Database::open(dir.join("replica.db")).unwrap(),
+        metrics: Arc::new(Metrics::new(Default::default())),
+        replica: Some(Arc::new(Replica::new("primary".to_string()))),
+        in_flight: InFlight::new(),
+        started: Instant::now(),
+        local: true,
+    };
+
+    // the merged rows would take ids that the primary's rows are copied in with later
+    let result = server
+        .clone()
+        .merge(context::current(), other.clone())
+        .await;
+    assert!(matches!(result, Err(RpcError::OtherError { .. })));
+    server.replica.as_ref().unwrap().promote();
+    server.merge(context::current(), other).await.unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_isearch_ranks_failures_last() {
+    use crate::udp::{insert, test_entry, RpcMessage};
+
+    let con = rusqlite::Connection::open_in_memory().unwrap();
+    crate::schema::create_schema(&con).unwrap();
+    for (dir, argv, exit_status) in [
+        ("/o", "make", 0),
+        ("/w", "mkae", 127),
+        // interrupted with ctrl-c
+        ("/w", "make -j", 130),
+    ] {
+        let msg = RpcMessage {
+            exit_status,
+            ..test_entry(dir, argv, 1)
+        };
+        insert(&con, &msg, None).unwrap();
+    }
+    let search = |include_failures, hide_not_found| {
+        let query = IsearchQuery {
+            command: String::new(),
+            dir: "/w".to_string(),
+            limit: 10,
+            offset: 0,
+            include_failures,
+            hide_not_found,
+        };
+        run_isearch(&con, query)
+            .unwrap()
+            .into_iter()
+            .map(|row| row.argv)
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(search(false, false), ["make -j", "make", "mkae"]);
+    assert_eq!(search(false, true), ["make -j", "make"]);
+    assert_eq!(search(true, true), ["make -j", "mkae", "make"]);
 }
@@ -1,149 +1,589 @@
 use anyhow::{anyhow, Context, Result};
-use rusqlite::params;
-use rusqlite::Connection;
+use rusqlite::{params, OptionalExtension};
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
-use std::time::SystemTime;
-use tokio::net::UdpSocket;
-use tokio::sync::Mutex;
-use tracing::error;
-use tracing::info;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
+use tokio::net::{UdpSocket, UnixDatagram};
+use tokio::sync::{mpsc, watch};
+use tracing::{debug, error, info, warn};
+
+use crate::db::Database;
+use crate::listen::{Allowlist, Listen};
+use crate::local::{ingest_socket_path, prepare_socket_path, restrict_socket};
+use crate::metrics::Histogram;
+use crate::repo::repo_identity;
 
 const MAX_DATAGRAM_SIZE: usize = 65_507;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct RpcMessage {
     pub host: String,
-    pub session: i32,
+    /// Globally unique id of the shell, generated by `history --eval`. Missing from datagrams
+    /// sent by shells that were set up before these existed.
+    pub session: Option<String>,
+    /// The /dev/pts number of the shell, if it has one
+    pub tty: Option<i32>,
     pub exit_status: i32,
     pub dir: String,
     pub argv: String,
     pub time: u64,
+    /// The entry's number in the shell's history list. Sending the same number again means
+    /// no new command was run (an empty line, or one kept out of the history), so it's dropped.
+    /// Missing from datagrams sent by older hooks.
+    pub history_number: Option<i32>,
+    /// Extra context, like ("branch", "main"), as configured under [context] in the config file
+    pub context: Vec<(String, String)>,
+}
+
+/// Datagrams that have been parsed but not yet written to the database. When the writer falls
+/// this far behind, the socket reader waits for it, and the kernel starts dropping datagrams.
+const CHANNEL_CAPACITY: usize = 4096;
+const SOCKET_RECV_BUFFER_SIZE: usize = 8 << 20;
+/// Most entries to write in a single transaction
+const MAX_BATCH_SIZE: usize = 512;
+
+/// Running totals for the ingestion pipeline
+#[derive(Default, Debug)]
+pub struct IngestStats {
+    pub received: AtomicU64,
+    pub parse_failures: AtomicU64,
+    pub inserted: AtomicU64,
+    pub insert_failures: AtomicU64,
+    /// Entries dropped because the shell sent the same history number again
+    pub duplicates: AtomicU64,
+    pub batches: AtomicU64,
+    pub largest_batch: AtomicU64,
+    /// Total time spent in write transactions
+    pub insert_micros: AtomicU64,
+    /// Parse failures broken down by `ParseError::label`
+    pub parse_errors: Mutex<BTreeMap<&'static str, u64>>,
+    pub insert_latency: Histogram,
+    /// Unix time of the last batch that inserted anything
+    pub last_insert: AtomicU64,
+}
+
+/// Why a datagram couldn't be parsed. Attached as context to the error from `deserialize`.
+#[derive(thiserror::Error, Debug, Clone, Copy)]
+enum ParseError {
+    #[error("Wrong number of null-separated fields")]
+    FieldCount,
+    #[error("Unable to parse tty")]
+    Tty,
+    #[error("Unable to parse exit status")]
+    ExitStatus,
+    #[error("Command line is missing its leading line number")]
+    Argv,
+    #[error("Unable to parse history number")]
+    HistoryNumber,
+    #[error("Unable to parse context")]
+    Context,
+}
+
+impl ParseError {
+    fn label(&self) -> &'static str {
+        match self {
+            ParseError::FieldCount => "field_count",
+            ParseError::Tty => "tty",
+            ParseError::ExitStatus => "exit_status",
+            ParseError::Argv => "argv",
+            ParseError::HistoryNumber => "history_number",
+            ParseError::Context => "context",
+        }
+    }
 }
 
+impl std::fmt::Display for IngestStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let batches = self.batches.load(Ordering::Relaxed);
+        let inserted = self.inserted.load(Ordering::Relaxed);
+        write!(
+            f,
+            "received={} parse_failures={} inserted={} insert_failures={} duplicates={} batches={} avg_batch={:.1} largest_batch={} avg_batch_ms={:.2}",
+            self.received.load(Ordering::Relaxed),
+            self.parse_failures.load(Ordering::Relaxed),
+            inserted,
+            self.insert_failures.load(Ordering::Relaxed),
+            self.duplicates.load(Ordering::Relaxed),
+            batches,
+            inserted as f64 / batches.max(1) as f64,
+            self.largest_batch.load(Ordering::Relaxed),
+            self.insert_micros.load(Ordering::Relaxed) as f64 / 1000.0 / batches.max(1) as f64,
+        )
+    }
+}
+
+/// Receives history entries over UDP. The socket reader only parses datagrams and hands them
+/// off to a writer task, which commits whatever has piled up in one transaction, so that a
+/// burst of datagrams (a script pasted into a bunch of shells) costs one fsync per batch rather
+/// than one per entry.
 pub struct InsertServer {
-    socket: UdpSocket,
+    socket: IngestSocket,
     buf: Vec<u8>,
-    con: Arc<Mutex<Connection>>,
+    db: Database,
+    stats: Arc<IngestStats>,
+}
+
+/// Where history comes in: UDP from the peers in the allowlist, or a Unix socket from this
+/// user on this machine
+enum IngestSocket {
+    Udp(UdpSocket, Arc<Allowlist>),
+    Unix(UnixDatagram),
+}
+
+impl IngestSocket {
+    /// Receive the next datagram from an allowed peer
+    async fn recv(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            IngestSocket::Udp(socket, allowlist) => loop {
+                let (nbytes, peer) = socket.recv_from(buf).await?;
+                if allowlist.allows(peer.ip(), "history") {
+                    return Ok(nbytes);
+                }
+            },
+            IngestSocket::Unix(socket) => socket.recv(buf).await,
+        }
+    }
 }
 
 impl InsertServer {
-    pub async fn new(con: Arc<Mutex<rusqlite::Connection>>) -> Result<InsertServer> {
-        let addr = format!("0.0.0.0:{}", crate::HISTORY_PORT);
-        info!("Lisening on {}", addr);
-        let socket = UdpSocket::bind(&addr).await?;
+    pub async fn new(
+        db: Database,
+        stats: Arc<IngestStats>,
+        listen: &Listen,
+    ) -> Result<InsertServer> {
+        let socket = match listen {
+            Listen::Network { bind, allowlist } => {
+                let addr = SocketAddr::new(*bind, crate::HISTORY_PORT);
+                info!("Lisening on {}", addr);
+                let socket = UdpSocket::bind(addr)
+                    .await
+                    .with_context(|| format!("Unable to bind {}", addr))?;
+                IngestSocket::Udp(socket, allowlist.clone())
+            }
+            Listen::Local => {
+                let path = ingest_socket_path()?;
+                prepare_socket_path(&path)?;
+                info!("Lisening on {}", path.display());
+                let socket = UnixDatagram::bind(&path)
+                    .with_context(|| format!("Unable to bind {}", path.display()))?;
+                restrict_socket(&path)?;
+                IngestSocket::Unix(socket)
+            }
+        };
+        // a bigger kernel buffer absorbs bursts while the writer is busy committing. the kernel
+        // clamps this to net.core.rmem_max, so failure here isn't worth more than a warning
+        let fd = match &socket {
+            IngestSocket::Udp(socket, _) => socket.as_raw_fd(),
+            IngestSocket::Unix(socket) => socket.as_raw_fd(),
+        };
+        if let Err(e) = nix::sys::socket::setsockopt(
+            fd,
+            nix::sys::socket::sockopt::RcvBuf,
+            &SOCKET_RECV_BUFFER_SIZE,
+        ) {
+            warn!("Unable to set receive buffer size: {}", e);
+        }
         Ok(InsertServer {
             socket,
             buf: vec![0; MAX_DATAGRAM_SIZE],
-            con,
+            db,
+            stats,
         })
     }
-    pub async fn run(self) -> Result<()> {
+    /// Receive until `shutdown` changes, then write whatever is still queued and return
+    pub async fn run(self, mut shutdown: watch::Receiver<bool>) -> Result<()> {
         let InsertServer {
             socket,
             mut buf,
-            con,
+            db,
+            stats,
         } = self;
 
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let writer = tokio::spawn(write_batches_forever(db, rx, stats.clone()));
+
         loop {
-            if let Err(e) = InsertServer::run_one(&con, &socket, &mut buf).await {
-                error!("{:#}", e);
+            let result = tokio::select! {
+                result = InsertServer::run_one(&socket, &mut buf, &stats) => result,
+                _ = shutdown.changed() => break,
+            };
+            match result {
+                Ok(msg) => {
+                    if tx.send(msg).await.is_err() {
+                        // the writer only stops if it panicked
+                        return writer.await.map_err(anyhow::Error::from);
+                    }
+                }
+                Err(e) => error!("{:#}", e),
             }
         }
+
+        info!(
+            "Stopped receiving history. Writing the {} entries still queued",
+            CHANNEL_CAPACITY - tx.capacity()
+        );
+        drop(tx);
+        writer.await.map_err(anyhow::Error::from)
     }
     async fn run_one(
-        con: &Arc<Mutex<Connection>>,
-        socket: &UdpSocket,
-        buf: &mut Vec<u8>,
-    ) -> Result<()> {
+        socket: &IngestSocket,
+        buf: &mut [u8],
+        stats: &IngestStats,
+    ) -> Result<RpcMessage> {
         let nbytes = socket
             .recv(buf)
             .await
             .context("Receiving bytes from socket")?;
-        let msg = deserialize(&buf[..nbytes])?;
-        insert(&*con.lock().await, &msg).context("Inserting into history database")?;
-        Ok(())
+        stats.received.fetch_add(1, Ordering::Relaxed);
+        deserialize(&buf[..nbytes]).inspect_err(|e| {
+            stats.parse_failures.fetch_add(1, Ordering::Relaxed);
+            let label = e
+                .downcast_ref::<ParseError>()
+                .map_or("other", ParseError::label);
+            *stats.parse_errors.lock().unwrap().entry(label).or_default() += 1;
+        })
     }
 }
 
-fn deserialize(buf: &[u8]) -> Result<RpcMessage> {
+async fn write_batches_forever(
+    db: Database,
+    mut rx: mpsc::Receiver<RpcMessage>,
+    stats: Arc<IngestStats>,
+) {
+    while let Some(first) = rx.recv().await {
+        let mut batch = vec![first];
+        while batch.len() < MAX_BATCH_SIZE {
+            match rx.try_recv() {
+                Ok(msg) => batch.push(msg),
+                Err(_) => break,
+            }
+        }
+
+        let n = batch.len() as u64;
+        let start = Instant::now();
+        let (failures, duplicates) = db
+            .write(move |con| Ok::<_, anyhow::Error>(insert_batch(con, &batch)))
+            .await
+            .unwrap_or_else(|e| {
+                error!("Writer task failed: {:#}", e);
+                (n, 0)
+            });
+        let elapsed = start.elapsed();
+
+        stats.batches.fetch_add(1, Ordering::Relaxed);
+        stats
+            .inserted
+            .fetch_add(n - failures - duplicates, Ordering::Relaxed);
+        stats.insert_failures.fetch_add(failures, Ordering::Relaxed);
+        stats.duplicates.fetch_add(duplicates, Ordering::Relaxed);
+        stats.largest_batch.fetch_max(n, Ordering::Relaxed);
+        stats
+            .insert_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        stats.insert_latency.observe(elapsed);
+        if failures + duplicates < n {
+            let now = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs());
+            stats.last_insert.store(now, Ordering::Relaxed);
+        }
+        debug!("Inserted batch of {} entries in {:?}", n, elapsed);
+    }
+}
+
+/// Insert all of `batch` in one transaction. If that fails, fall back to inserting the entries
+/// one at a time, so one bad entry doesn't take the rest of the batch down with it. Returns the
+/// number of entries that couldn't be inserted, and the number dropped as duplicates.
+fn insert_batch(con: &mut rusqlite::Connection, batch: &[RpcMessage]) -> (u64, u64) {
+    let in_transaction = |con: &mut rusqlite::Connection, msgs: &[RpcMessage]| -> Result<u64> {
+        let tx = con.transaction()?;
+        let mut duplicates = 0;
+        for msg in msgs {
+            if !insert(&tx, msg, None)? {
+                duplicates += 1;
+            }
+        }
+        tx.commit()?;
+        Ok(duplicates)
+    };
+
+    match in_transaction(con, batch) {
+        Ok(duplicates) => (0, duplicates),
+        Err(e) if batch.len() == 1 => {
+            error!("Inserting into history database: {:#}", e);
+            (1, 0)
+        }
+        Err(e) => {
+            error!(
+                "Inserting batch of {} into history database: {:#}. Retrying individually",
+                batch.len(),
+                e
+            );
+            batch
+                .chunks(1)
+                .map(|msg| insert_batch(con, msg))
+                .fold((0, 0), |(f, d), (f1, d1)| (f + f1, d + d1))
+        }
+    }
+}
+
+pub(crate) fn deserialize<'a>(buf: &'a [u8]) -> Result<RpcMessage> {
     let ctx = || {
         format!(
             "Failure to parse UDP datagram {:#?}",
             String::from_utf8_lossy(buf)
         )
     };
+    let int_field = |v: &[u8], what: &str, kind: ParseError| {
+        String::from_utf8_lossy(v)
+            .parse::<i32>()
+            .with_context(|| {
+                format!(
+                    "Unable to parse {} {:#?} as i32",
+                    what,
+                    String::from_utf8_lossy(v)
+                )
+            })
+            .context(kind)
+            .with_context(ctx)
+    };
+
+    // older hooks send the output of `history 1`, which starts with the entry's number padded to
+    // five digits and two spaces. that goes wrong past 99999, or with HISTTIMEFORMAT set
+    let strip_line_number = |v: &'a [u8]| {
+        v.get(7..).ok_or_else(|| anyhow!("The command line (last field), ostensibly from $(history 1) is too short, and doesn't contain the expected leading line number"))
+            .context(ParseError::Argv)
+            .with_context(ctx)
+    };
+    let session_field =
+        |v: &[u8]| Some(String::from_utf8_lossy(v).to_string()).filter(|s| !s.is_empty());
+    let tty_field = |v: &[u8]| -> Result<Option<i32>> {
+        match v.is_empty() {
+            true => Ok(None),
+            false => Ok(Some(int_field(v, "tty (third field)", ParseError::Tty)?)),
+        }
+    };
 
     let fields: Vec<&[u8]> = buf.split(|&c| c == b'\0').collect();
-    match &fields[..] {
-        [v_session, v_hostname, v_exit_status, v_pwd, v_argv_with_line_number] => {
-            let session = String::from_utf8_lossy(v_session)
-                .parse::<i32>()
-                .with_context(|| {
-                    format!(
-                        "Unable to parse session id (first field) {:#?} as i32",
-                        String::from_utf8_lossy(v_session)
-                    )
-                })
-                .with_context(ctx)?;
-            let host = String::from_utf8_lossy(v_hostname).to_string();
-            let exit_status = String::from_utf8_lossy(v_exit_status)
-                .parse::<i32>()
-                .with_context(|| {
+    let (session, tty, v_hostname, v_exit_status, v_pwd, history_number, v_context, v_argv) =
+        match &fields[..] {
+            // any number of key=value context fields come between the history number and the command
+            [b"v3", v_session, v_tty, v_hostname, v_exit_status, v_pwd, v_history_number, v_context @ .., v_argv] => {
+                (
+                    session_field(v_session),
+                    tty_field(v_tty)?,
+                    v_hostname,
+                    v_exit_status,
+                    v_pwd,
+                    Some(int_field(
+                        v_history_number,
+                        "history number (seventh field)",
+                        ParseError::HistoryNumber,
+                    )?),
+                    v_context,
+                    *v_argv,
+                )
+            }
+            [b"v2", v_session, v_tty, v_hostname, v_exit_status, v_pwd, v_argv_with_line_number] => {
+                (
+                    session_field(v_session),
+                    tty_field(v_tty)?,
+                    v_hostname,
+                    v_exit_status,
+                    v_pwd,
+                    None,
+                    &[][..],
+                    strip_line_number(v_argv_with_line_number)?,
+                )
+            }
+            // sent by shells that eval'd the hook before it included a unique session id
+            [v_tty, v_hostname, v_exit_status, v_pwd, v_argv_with_line_number] => (
+                None,
+                Some(int_field(
+                    v_tty,
+                    "session id (first field)",
+                    ParseError::Tty,
+                )?),
+                v_hostname,
+                v_exit_status,
+                v_pwd,
+                None,
+                &[][..],
+                strip_line_number(v_argv_with_line_number)?,
+            ),
+            _ => {
+                return Err(anyhow!(ParseError::FieldCount)).with_context(|| {
                     format!(
-                        "Unable to parse exit status (third field) {:#?} as i32",
-                        String::from_utf8_lossy(v_exit_status)
+                        "Unable to parse UDP datagram {:#?} as null-separated fields",
+                        String::from_utf8_lossy(buf)
                     )
-                })
-                .with_context(ctx)?;
-            let dir = String::from_utf8_lossy(v_pwd).to_string();
-            let v_argv_without_line_number = v_argv_with_line_number.get(7..);
-            let v_argv = v_argv_without_line_number.ok_or_else(|| anyhow!("The command line (third field), ostensibly from $(history 1) is too short, and doesn't contain the expected leading line number"))
-            .with_context(ctx)?;
-            Ok(RpcMessage {
-                session,
-                host,
-                exit_status,
-                dir,
-                argv: String::from_utf8_lossy(v_argv).to_string(),
-                time: SystemTime::now()
-                    .duration_since(SystemTime::UNIX_EPOCH)?
-                    .as_secs(),
-            })
-        }
-        _ => {
-            anyhow::bail!(
-                "Unable to parse UDP datagram {:#?} as 5 null-separated fields",
-                String::from_utf8_lossy(buf)
-            );
+                });
+            }
+        };
+
+    let exit_status = int_field(v_exit_status, "exit status", ParseError::ExitStatus)?;
+    let context = v_context
+        .iter()
+        .map(|v| {
+            let v = String::from_utf8_lossy(v);
+            v.split_once('=')
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .ok_or_else(|| anyhow!("Context field {:#?} isn't key=value", v))
+                .context(ParseError::Context)
+                .with_context(ctx)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(RpcMessage {
+        session,
+        tty,
+        host: String::from_utf8_lossy(v_hostname).to_string(),
+        exit_status,
+        dir: String::from_utf8_lossy(v_pwd).to_string(),
+        argv: String::from_utf8_lossy(v_argv).to_string(),
+        time: SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_secs(),
+        history_number,
+        context,
+    })
+}
+
+/// Insert one entry. `id` is only given when copying rows from a primary server, so that
+/// replicas keep the same history ids. Returns false, without inserting anything, if the
+/// session's latest entry has the same history number.
+pub(crate) fn insert(
+    con: &rusqlite::Connection,
+    msg: &RpcMessage,
+    id: Option<i64>,
+) -> Result<bool> {
+    if let (Some(session), Some(number)) = (&msg.session, msg.history_number) {
+        let latest: Option<Option<i32>> = con
+            .prepare_cached(
+                "select history_number from history where session_id = ? order by id desc limit 1",
+            )?
+            .query_row(params![session], |row| row.get(0))
+            .optional()?;
+        if latest == Some(Some(number)) {
+            return Ok(false);
         }
     }
+    // the no-op updates are so that RETURNING gives us the id of an existing row
+    let command_id: i64 = con
+        .prepare_cached(
+            "insert into commands (argv) values (?)
+            on conflict (argv) do update set argv = excluded.argv
+            returning id",
+        )?
+        .query_row(params![msg.argv], |row| row.get(0))?;
+    let place_id: i64 = con
+        .prepare_cached(
+            "insert into places (host, dir) values (?, ?)
+            on conflict (host, dir) do update set dir = excluded.dir
+            returning id",
+        )?
+        .query_row(params![msg.host, msg.dir], |row| row.get(0))?;
+    con.prepare_cached(
+        "insert into history (id, session, session_id, command_id, place_id, exit_status, end_time, history_number)
+                                  values (?, ?, ?, ?, ?, ?, ?, ?)",
+    )?
+    .execute(params![
+        id,
+        msg.tty,
+        msg.session,
+        command_id,
+        place_id,
+        msg.exit_status,
+        msg.time,
+        msg.history_number
+    ])?;
+    let history_id = con.last_insert_rowid();
+    for (key, value) in &msg.context {
+        // the hook sends the remote's URL as it's written in the git config, which depends on
+        // how the repository was cloned
+        let value = match key.as_str() {
+            "repo_id" => repo_identity(value),
+            _ => value.clone(),
+        };
+        con.prepare_cached(
+            "insert or replace into context (history_id, key, value) values (?, ?, ?)",
+        )?
+        .execute(params![history_id, key, value])?;
+    }
+
+    Ok(true)
 }
 
-fn insert(con: &rusqlite::Connection, msg: &RpcMessage) -> Result<()> {
-    let command_id = match con
-        .prepare("insert into commands (argv) values (?)")?
-        .insert(params![msg.argv])
-    {
-        Ok(i) => i,
-        Err(_) => con
-            .prepare("select id from commands where argv = ?")?
-            .query_row(params![msg.argv], |row| row.get(0))?,
-    };
-    let place_id = match con
-        .prepare("insert into places (host, dir) values (?, ?)")?
-        .insert(params![msg.host, msg.dir])
-    {
-        Ok(i) => i,
-        Err(_) => con
-            .prepare("select id from places where host = ? AND dir = ?")?
-            .query_row(params![msg.host, msg.dir], |row| row.get(0))?,
-    };
-    con.execute(
-        "insert into history (session, command_id, place_id, exit_status, end_time)
-                                  values (?, ?, ?, ?, ?)",
-        params![msg.session, command_id, place_id, msg.exit_status, msg.time],
-    )?;
+/// A successful run of `argv` in `dir` at `time`, from a shell on "myhost", for tests to insert
+#[cfg(test)]
+pub(crate) fn test_entry(dir: &str, argv: &str, time: u64) -> RpcMessage {
+    RpcMessage {
+        host: "myhost".to_string(),
+        session: Some("myhost:1:a".to_string()),
+        tty: Some(1),
+        exit_status: 0,
+        dir: dir.to_string(),
+        argv: argv.to_string(),
+        time,
+        history_number: None,
+        context: Vec::new(),
+    }
+}
+
+#[test]
+fn test_deserialize() {
+    let msg =
+        deserialize(b"v2\x00myhost:123:abc\x00\x00myhost\x00127\x00/tmp\x00    1  sl").unwrap();
+    assert_eq!(msg.session.as_deref(), Some("myhost:123:abc"));
+    assert_eq!(msg.tty, None);
+    assert_eq!(msg.exit_status, 127);
+    assert_eq!(msg.argv, "sl");
+
+    let msg =
+        deserialize(b"v3\x00id\x003\x00myhost\x000\x00/tmp\x00123456\x00cat <<X\n  a\nX").unwrap();
+    assert_eq!(msg.tty, Some(3));
+    assert_eq!(msg.history_number, Some(123456));
+    assert_eq!(msg.argv, "cat <<X\n  a\nX");
+    let msg =
+        deserialize(b"v3\x00id\x00\x00myhost\x000\x00/tmp\x001\x00branch=a=b\x00shlvl=1\x00ls")
+            .unwrap();
+    assert_eq!(
+        msg.context,
+        [("branch", "a=b"), ("shlvl", "1")].map(|(k, v)| (k.to_string(), v.to_string()))
+    );
+    assert_eq!(msg.argv, "ls");
 
-    Ok(())
+    let msg = deserialize(b"4\x00myhost\x000\x00/tmp\x00    1  ls -l").unwrap();
+    assert_eq!(msg.session, None);
+    assert_eq!(msg.tty, Some(4));
+    assert_eq!(msg.dir, "/tmp");
+    assert_eq!(msg.argv, "ls -l");
+
+    let err = deserialize(b"v2\x00id\x00pts\x00myhost\x000\x00/tmp\x00    1  ls").unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<ParseError>().map(ParseError::label),
+        Some("tty")
+    );
+    let err = deserialize(b"myhost\x000\x00/tmp").unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<ParseError>().map(ParseError::label),
+        Some("field_count")
+    );
+}
+
+#[test]
+fn test_insert_drops_repeated_history_number() {
+    let con = rusqlite::Connection::open_in_memory().unwrap();
+    crate::schema::create_schema(&con).unwrap();
+    let entry = |session: &str, number: i32, argv: &str| {
+        deserialize(format!("v3\0{session}\0\0h\x000\0/tmp\0{number}\0{argv}").as_bytes()).unwrap()
+    };
+    assert!(insert(&con, &entry("a", 1, "ls"), None).unwrap());
+    // enter on an empty line
+    assert!(!insert(&con, &entry("a", 1, "ls"), None).unwrap());
+    assert!(insert(&con, &entry("b", 1, "ls"), None).unwrap());
+    assert!(insert(&con, &entry("a", 2, "ls"), None).unwrap());
 }
@@ -41,6 +41,20 @@ pub fn getshorthostname() -> String {
         .to_string()
 }
 
+/// Generate an id for a new shell session that won't collide with any other shell on any host,
+/// unlike the /dev/pts number, which is reused as soon as the terminal closes.
+pub fn new_session_id() -> String {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    // the parent is the shell that's evaluating our output
+    let ppid = nix::unistd::getppid();
+    format!("{}:{}:{:x}", getshorthostname(), ppid, nanos)
+}
+
+/// The /dev/pts number of this process's controlling terminal. Fails when there isn't one,
+/// e.g. under cron or ssh without a pty.
 pub fn getsession() -> Result<i32> {
     crate::_vendor_ctty::get_path_for_dev(
         crate::_vendor_ctty::get_ctty_dev()
@@ -55,9 +69,10 @@ pub fn getsession() -> Result<i32> {
 pub async fn addr_routes_to_me(addr: &str) -> Result<bool> {
     let server = UdpSocket::bind("0.0.0.0:0").await?;
     let client = UdpSocket::bind("0.0.0.0:0").await?;
-    if let Err(_) = client
+    if client
         .connect(format!("{}:{}", addr, server.local_addr()?.port()))
         .await
+        .is_err()
     {
         return Ok(false);
     };
@@ -78,6 +93,7 @@ pub async fn addr_routes_to_me(addr: &str) -> Result<bool> {
 }
 
 #[tokio::test]
+#[allow(clippy::bool_assert_comparison)]
 async fn test_1() {
     assert_eq!(addr_routes_to_me("127.0.0.1").await.unwrap(), true);
     assert_eq!(